@@ -0,0 +1,428 @@
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    compiler::CudaCompiler,
+    cuda::{compile_ptx, has_errors, CompileError},
+    diagnostics::{Diagnostic, Severity},
+    hip::{compile_code_object, HipCompiler, HIP_ENV_VARS},
+    hipify::hipify,
+    includes::resolve_includes,
+    opencl::{run_clang, validate_opencl as check_lexically, Clang, ClangOutput, OPENCL_ENV_VARS},
+    options::{resolve_path, CudaOptions, CUDA_ENV_VARS},
+};
+
+/// The error of a build script function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// A file could not be read or written, or a compiler could not be run.
+    Io(String),
+    /// A kernel was rejected, the diagnostics refer to lines of `file`.
+    Diagnostics {
+        file: PathBuf,
+        diagnostics: Vec<Diagnostic>,
+    },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Io(message) => f.write_str(message),
+            BuildError::Diagnostics { file, diagnostics } => {
+                let messages = diagnostics
+                    .iter()
+                    .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                    .map(|diagnostic| located(file, diagnostic))
+                    .collect::<Vec<_>>();
+                f.write_str(&messages.join("\n"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Finds all files with one of the `extensions` in `dir` and its subdirectories, sorted by path.
+/// Hidden files and directories are skipped.
+pub fn find_kernels(dir: &Path, extensions: &[&str]) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    walk(dir, extensions, &mut paths)?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Collects all files with one of the `extensions` below `dir`, skipping hidden entries.
+fn walk(dir: &Path, extensions: &[&str], paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+
+        if is_hidden {
+            continue;
+        }
+        if path.is_dir() {
+            walk(&path, extensions, paths)?;
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| extensions.contains(&ext))
+        {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Compiles a CUDA kernel file to PTX and writes it to `OUT_DIR`, e.g. `kernels/add.cu` to `$OUT_DIR/kernels/add.ptx`.
+///
+/// Uses the compiler, options and cache of `cuda!`, configured with the same `CUSTOS_CUDA_*` variables.
+/// The path is relative to the crate root and kept by the output, so kernels with the same name do not collide.
+/// Returns the path of the PTX file.
+///
+/// ```ignore
+/// // build.rs
+/// let ptx = custos_macro_build::compile_cuda("kernels/add.cu")?;
+/// ```
+pub fn compile_cuda(path: impl AsRef<Path>) -> Result<PathBuf, BuildError> {
+    rerun_if_env_changed(CUDA_ENV_VARS);
+    let path = resolve_path(path);
+    compile_cuda_file(&path, &output_path(&path).with_extension("ptx"))
+}
+
+/// Compiles every `.cu` file of a directory to PTX, see [`compile_cuda`].
+///
+/// The PTX files keep their paths relative to the crate root,
+/// e.g. `src/kernels/nn/relu.cu` becomes `$OUT_DIR/src/kernels/nn/relu.ptx` for `compile_cuda_dir("src/kernels")`.
+/// Returns the paths of the PTX files.
+pub fn compile_cuda_dir(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, BuildError> {
+    rerun_if_env_changed(CUDA_ENV_VARS);
+    compile_dir(dir.as_ref(), |path, output| {
+        compile_cuda_file(path, &output.with_extension("ptx"))
+    })
+}
+
+fn compile_cuda_file(path: &Path, output: &Path) -> Result<PathBuf, BuildError> {
+    let (src, options, includes) = read_kernel(path)?;
+    let compiled = compile_ptx(&src, &includes, &options, &CudaCompiler::from_env())
+        .map_err(|err| build_error(path, err))?;
+    warn(path, &compiled.diagnostics);
+    write_out(output, compiled.ptx.as_bytes())
+}
+
+/// Translates a CUDA kernel file to HIP like `hip!` and compiles it to a code object with `CUSTOS_HIP_COMPILER`,
+/// e.g. `kernels/add.cu` to `$OUT_DIR/kernels/add.hsaco`. Returns the path of the written file.
+///
+/// Without `CUSTOS_HIP_COMPILER`, the translated source including the `defines` is written instead,
+/// e.g. to `$OUT_DIR/kernels/add.hip`, to be compiled at runtime.
+///
+/// ```ignore
+/// // build.rs
+/// let code_object = custos_macro_build::compile_hip("kernels/add.cu")?;
+/// ```
+pub fn compile_hip(path: impl AsRef<Path>) -> Result<PathBuf, BuildError> {
+    rerun_if_env_changed(CUDA_ENV_VARS);
+    rerun_if_env_changed(HIP_ENV_VARS);
+    let path = resolve_path(path);
+    compile_hip_file(&path, &output_path(&path))
+}
+
+/// Compiles every `.cu` file of a directory with [`compile_hip`].
+/// The outputs keep their paths relative to the crate root, like the PTX files of [`compile_cuda_dir`].
+pub fn compile_hip_dir(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, BuildError> {
+    rerun_if_env_changed(CUDA_ENV_VARS);
+    rerun_if_env_changed(HIP_ENV_VARS);
+    compile_dir(dir.as_ref(), compile_hip_file)
+}
+
+fn compile_hip_file(path: &Path, output: &Path) -> Result<PathBuf, BuildError> {
+    let (src, options, includes) = read_kernel(path)?;
+    let Some(compiler) = HipCompiler::from_env(&options) else {
+        let src = hipify(&(options.source_preamble() + &src));
+        return write_out(&output.with_extension("hip"), src.as_bytes());
+    };
+
+    let (bin, diagnostics) = compile_code_object(&hipify(&src), &includes, &options, &compiler)
+        .map_err(|err| build_error(path, err))?;
+    warn(path, &diagnostics);
+    write_out(&output.with_extension("hsaco"), &bin)
+}
+
+/// Compiles every `.cu` file below `dir` to its [`output_path`].
+fn compile_dir(
+    dir: &Path,
+    compile: impl Fn(&Path, &Path) -> Result<PathBuf, BuildError>,
+) -> Result<Vec<PathBuf>, BuildError> {
+    let root = resolve_path(dir);
+    let paths = find_kernels(&root, &["cu"]).map_err(|err| {
+        BuildError::Io(format!(
+            "Could not read the kernel directory {}: {err}",
+            root.display()
+        ))
+    })?;
+    println!("cargo:rerun-if-changed={}", root.display());
+
+    paths
+        .iter()
+        .map(|path| compile(path, &output_path(path)))
+        .collect()
+}
+
+/// The path of the output of a kernel relative to `OUT_DIR`, before its extension is replaced:
+/// the path of the kernel relative to the crate root, so kernels with the same name in different directories
+/// do not overwrite each other. Paths outside of the crate keep all their normal components,
+/// e.g. `../shared/add.cu` becomes `shared/add.cu`.
+fn output_path(path: &Path) -> PathBuf {
+    let root = resolve_path("");
+    path.strip_prefix(&root)
+        .unwrap_or(path)
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// Reads a CUDA kernel file, resolves its includes and tells cargo to rerun if one of them changes.
+/// The directory of the file is an include dir of the returned options.
+fn read_kernel(path: &Path) -> Result<(String, CudaOptions, Vec<PathBuf>), BuildError> {
+    let src = read(path)?;
+    let mut options = CudaOptions::from_env();
+    options
+        .include_dirs
+        .extend(path.parent().map(Path::to_path_buf));
+    let includes = resolve_includes(&src, path.parent(), &options.include_dirs);
+    for file in std::iter::once(path).chain(includes.iter().map(PathBuf::as_path)) {
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+    Ok((src, options, includes))
+}
+
+fn build_error(path: &Path, err: CompileError) -> BuildError {
+    match err {
+        CompileError::Io(message) => BuildError::Io(message),
+        CompileError::Diagnostics(diagnostics) => BuildError::Diagnostics {
+            file: path.to_path_buf(),
+            diagnostics,
+        },
+    }
+}
+
+/// Parses and validates a WGSL shader with naga and writes it as SPIR-V to `OUT_DIR`,
+/// e.g. `shaders/fill.wgsl` to `$OUT_DIR/shaders/fill.spv`. Returns the path of the SPIR-V file.
+///
+/// Performs the same checks as `wgsl!` without extra `capabilities`.
+#[cfg(feature = "wgsl")]
+pub fn compile_wgsl(path: impl AsRef<Path>) -> Result<PathBuf, BuildError> {
+    use crate::shader::{parse_wgsl, validate, write_spirv};
+    use naga::valid::Capabilities;
+
+    let path = resolve_path(path);
+    println!("cargo:rerun-if-changed={}", path.display());
+    let src = read(&path)?;
+
+    let words = parse_wgsl(&src)
+        .and_then(|module| {
+            let info = validate(&module, &src, Capabilities::default())?;
+            write_spirv(&module, &info)
+        })
+        .map_err(|diagnostics| BuildError::Diagnostics {
+            file: path.clone(),
+            diagnostics,
+        })?;
+
+    let bytes = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+    write_out(&output_path(&path).with_extension("spv"), &bytes)
+}
+
+/// Checks an OpenCL C kernel file like `opencl!`: lexically and, if `CUSTOS_OPENCL_CLANG` is set,
+/// with `clang -cl-std=CL2.0 -fsyntax-only`. Warnings are passed on to cargo.
+pub fn validate_opencl(path: impl AsRef<Path>) -> Result<(), BuildError> {
+    rerun_if_env_changed(OPENCL_ENV_VARS);
+    let path = resolve_path(path);
+    println!("cargo:rerun-if-changed={}", path.display());
+    let src = read(&path)?;
+
+    let mut diagnostics = check_lexically(&src);
+    if let Some(clang) = Clang::from_env().filter(|_| !has_errors(&diagnostics)) {
+        let (_, checked) = run_clang(&src, path.parent(), &clang, ClangOutput::SyntaxOnly)
+            .map_err(BuildError::Io)?;
+        diagnostics.extend(checked);
+    }
+
+    if has_errors(&diagnostics) {
+        return Err(BuildError::Diagnostics {
+            file: path,
+            diagnostics,
+        });
+    }
+    warn(&path, &diagnostics);
+    Ok(())
+}
+
+fn rerun_if_env_changed(vars: &[&str]) {
+    for var in vars {
+        println!("cargo:rerun-if-env-changed={var}");
+    }
+}
+
+fn read(path: &Path) -> Result<String, BuildError> {
+    std::fs::read_to_string(path)
+        .map_err(|err| BuildError::Io(format!("Could not read {}: {err}", path.display())))
+}
+
+/// Writes a file relative to `OUT_DIR`.
+fn write_out(relative: &Path, contents: &[u8]) -> Result<PathBuf, BuildError> {
+    let out_dir = std::env::var_os("OUT_DIR").ok_or_else(|| {
+        BuildError::Io("OUT_DIR is not set, call this function from a build script".to_string())
+    })?;
+    let path = Path::new(&out_dir).join(relative);
+    let io_err =
+        |err: std::io::Error| BuildError::Io(format!("Could not write {}: {err}", path.display()));
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(io_err)?;
+    }
+    std::fs::write(&path, contents).map_err(io_err)?;
+    Ok(path)
+}
+
+/// Passes the warnings of a compiler on to cargo.
+fn warn(file: &Path, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        if diagnostic.severity == Severity::Warning {
+            println!(
+                "cargo:warning={}",
+                located(file, diagnostic).replace('\n', " ")
+            );
+        }
+    }
+}
+
+/// `kernels/add.cu:3:5: error: ...`
+fn located(file: &Path, diagnostic: &Diagnostic) -> String {
+    let severity = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Note => "note",
+    };
+    let file = diagnostic
+        .file
+        .clone()
+        .unwrap_or_else(|| file.display().to_string());
+    let location = match (diagnostic.line, diagnostic.column) {
+        (Some(line), Some(column)) => format!("{file}:{line}:{column}"),
+        (Some(line), None) => format!("{file}:{line}"),
+        _ => file,
+    };
+
+    let mut message = format!("{location}: {severity}: {}", diagnostic.message);
+    for context in &diagnostic.context {
+        message.push('\n');
+        message.push_str(context);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    /// The build script functions with a shell script as `CUSTOS_CUDA_COMPILER`.
+    ///
+    /// They are configured with environment variables, so everything runs in one test.
+    /// `OUT_DIR` points to a temporary directory, which also holds the cache.
+    #[cfg(unix)]
+    #[test]
+    fn compiles_directories_with_a_stub() {
+        use std::{os::unix::fs::PermissionsExt, path::Path};
+
+        use super::{compile_cuda, compile_cuda_dir, compile_hip_dir, BuildError};
+
+        /// Invoked like nvcc, rejects `undefined_symbol` and otherwise writes PTX with the first line of its input.
+        const STUB: &str = r#"#!/bin/sh
+[ "$1" = "--version" ] && { echo "stub nvcc 1.0"; exit 0; }
+for arg; do
+    [ "$prev" = "-o" ] && out="$arg"
+    case "$arg" in *.cu) input="$arg";; esac
+    prev="$arg"
+done
+line=$(grep -n undefined_symbol "$input" | head -n 1 | cut -d: -f1)
+if [ -n "$line" ]; then
+    echo "$input($line): error: identifier \"undefined_symbol\" is undefined" >&2
+    exit 2
+fi
+{ head -n 1 "$input"; echo ".visible .entry add()"; } > "$out"
+"#;
+
+        let dir =
+            std::env::temp_dir().join(format!("custos-macro-build-script-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let write = |relative: &str, contents: &str| {
+            let path = dir.join(relative);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+        };
+        write("nvcc", STUB);
+        std::fs::set_permissions(dir.join("nvcc"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        write(
+            "kernels/a/add.cu",
+            "// a\n__global__ void add(float* x) {}\n",
+        );
+        write(
+            "kernels/b/add.cu",
+            "// b\n__global__ void add(float* x) {}\n",
+        );
+        write("kernels/.hidden/add.cu", "// hidden\n");
+        write(
+            "broken/add.cu",
+            "// broken\n__global__ void add(float* x) {\n    x[0] = undefined_symbol;\n}\n",
+        );
+
+        let out_dir = dir.join("out");
+        std::env::set_var("OUT_DIR", &out_dir);
+        std::env::set_var("CUSTOS_CUDA_COMPILER", dir.join("nvcc"));
+
+        // the kernels are outside of the crate, their outputs keep the whole path
+        let out = |kernel: &str| out_dir.join(dir.strip_prefix("/").unwrap()).join(kernel);
+        let ptx = compile_cuda_dir(dir.join("kernels")).unwrap();
+        assert_eq!(ptx, [out("kernels/a/add.ptx"), out("kernels/b/add.ptx")]);
+        assert!(std::fs::read_to_string(&ptx[0])
+            .unwrap()
+            .starts_with("// a\n"));
+        assert!(std::fs::read_to_string(&ptx[1])
+            .unwrap()
+            .starts_with("// b\n"));
+        assert_eq!(compile_cuda(dir.join("kernels/b/add.cu")).unwrap(), ptx[1]);
+
+        let hip = compile_hip_dir(dir.join("kernels")).unwrap();
+        assert_eq!(hip, [out("kernels/a/add.hip"), out("kernels/b/add.hip")]);
+
+        let Err(BuildError::Diagnostics { file, diagnostics }) =
+            compile_cuda(dir.join("broken/add.cu"))
+        else {
+            panic!("the stub accepted the kernel");
+        };
+        assert_eq!(file, dir.join("broken/add.cu"));
+        assert_eq!(diagnostics[0].line, Some(3));
+        let err = BuildError::Diagnostics {
+            file: Path::new("broken/add.cu").to_path_buf(),
+            diagnostics,
+        };
+        assert_eq!(
+            err.to_string(),
+            "broken/add.cu:3: error: identifier \"undefined_symbol\" is undefined"
+        );
+
+        assert!(matches!(
+            compile_cuda_dir(dir.join("missing")),
+            Err(BuildError::Io(_))
+        ));
+
+        std::env::remove_var("OUT_DIR");
+        std::env::remove_var("CUSTOS_CUDA_COMPILER");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+/// A token of C-like kernel source code (CUDA, OpenCL C, HIP).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    /// Byte offset in the source.
+    pub offset: usize,
+    /// 1-based line.
+    pub line: usize,
+    /// 1-based column, counted in chars.
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Ident,
+    Number,
+    Str,
+    Char,
+    Punct,
+    Comment,
+    Whitespace,
+    /// A string, char or block comment that is missing its end.
+    Unterminated,
+}
+
+/// Splits `src` into tokens. Concatenating the text of all tokens yields `src` again.
+pub fn tokenize(src: &str) -> Vec<Token<'_>> {
+    let bytes = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    let mut line = 1;
+    let mut column = 1;
+
+    while pos < bytes.len() {
+        let start = pos;
+        let c = bytes[pos];
+
+        let kind = if c.is_ascii_whitespace() {
+            pos = scan_while(bytes, pos, |c| c.is_ascii_whitespace());
+            TokenKind::Whitespace
+        } else if src[pos..].starts_with("//") {
+            pos = src[pos..].find('\n').map_or(bytes.len(), |end| pos + end);
+            TokenKind::Comment
+        } else if src[pos..].starts_with("/*") {
+            match src[pos + 2..].find("*/") {
+                Some(end) => {
+                    pos += end + 4;
+                    TokenKind::Comment
+                }
+                None => {
+                    pos = bytes.len();
+                    TokenKind::Unterminated
+                }
+            }
+        } else if c == b'"' || c == b'\'' {
+            let (end, terminated) = scan_quoted(bytes, pos);
+            pos = end;
+            match (terminated, c) {
+                (false, _) => TokenKind::Unterminated,
+                (true, b'"') => TokenKind::Str,
+                (true, _) => TokenKind::Char,
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            pos = scan_while(bytes, pos, |c| c.is_ascii_alphanumeric() || c == b'_');
+            TokenKind::Ident
+        } else if c.is_ascii_digit()
+            || (c == b'.' && bytes.get(pos + 1).is_some_and(u8::is_ascii_digit))
+        {
+            pos = scan_number(bytes, pos);
+            TokenKind::Number
+        } else {
+            pos += src[pos..].chars().next().map_or(1, char::len_utf8);
+            TokenKind::Punct
+        };
+
+        let text = &src[start..pos];
+        tokens.push(Token {
+            kind,
+            text,
+            offset: start,
+            line,
+            column,
+        });
+
+        for c in text.chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+    }
+
+    tokens
+}
+
+/// Replaces identifiers outside of comments and literals.
+pub fn replace_idents(src: &str, mut replace: impl FnMut(&str) -> Option<String>) -> String {
+    tokenize(src)
+        .into_iter()
+        .map(|token| match token.kind {
+            TokenKind::Ident => replace(token.text).unwrap_or_else(|| token.text.to_string()),
+            _ => token.text.to_string(),
+        })
+        .collect()
+}
+
+fn scan_while(bytes: &[u8], mut pos: usize, f: impl Fn(u8) -> bool) -> usize {
+    while pos < bytes.len() && f(bytes[pos]) {
+        pos += 1;
+    }
+    pos
+}
+
+/// Returns the end of the literal and whether it is terminated.
+fn scan_quoted(bytes: &[u8], start: usize) -> (usize, bool) {
+    let quote = bytes[start];
+    let mut pos = start + 1;
+
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'\\' => pos += 2,
+            b'\n' => return (pos, false),
+            c if c == quote => return (pos + 1, true),
+            _ => pos += 1,
+        }
+    }
+    (bytes.len(), false)
+}
+
+/// Scans a number including suffixes and exponents, e.g. `1.5e-3f` or `0xffu`.
+fn scan_number(bytes: &[u8], mut pos: usize) -> usize {
+    while pos < bytes.len() {
+        let c = bytes[pos];
+        let is_exponent = matches!(c, b'e' | b'E' | b'p' | b'P')
+            && matches!(bytes.get(pos + 1), Some(b'+' | b'-'));
+
+        if is_exponent {
+            pos += 2;
+        } else if c.is_ascii_alphanumeric() || c == b'.' || c == b'_' {
+            pos += 1;
+        } else {
+            break;
+        }
+    }
+    pos
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replace_idents, tokenize, TokenKind};
+
+    fn kinds(src: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(src)
+            .into_iter()
+            .filter(|token| token.kind != TokenKind::Whitespace)
+            .map(|token| (token.kind, token.text))
+            .collect()
+    }
+
+    #[test]
+    fn token_kinds() {
+        assert_eq!(
+            kinds("x[i] += 1.5e-3f; // done\nc = 'a'; s = \"\\\"\"; /* b */ h = 0xffu;"),
+            [
+                (TokenKind::Ident, "x"),
+                (TokenKind::Punct, "["),
+                (TokenKind::Ident, "i"),
+                (TokenKind::Punct, "]"),
+                (TokenKind::Punct, "+"),
+                (TokenKind::Punct, "="),
+                (TokenKind::Number, "1.5e-3f"),
+                (TokenKind::Punct, ";"),
+                (TokenKind::Comment, "// done"),
+                (TokenKind::Ident, "c"),
+                (TokenKind::Punct, "="),
+                (TokenKind::Char, "'a'"),
+                (TokenKind::Punct, ";"),
+                (TokenKind::Ident, "s"),
+                (TokenKind::Punct, "="),
+                (TokenKind::Str, "\"\\\"\""),
+                (TokenKind::Punct, ";"),
+                (TokenKind::Comment, "/* b */"),
+                (TokenKind::Ident, "h"),
+                (TokenKind::Punct, "="),
+                (TokenKind::Number, "0xffu"),
+                (TokenKind::Punct, ";"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_columns_and_roundtrip() {
+        let src = "int a;\n/* two\nlines */ float ü = .5;\n";
+        let tokens = tokenize(src);
+        assert_eq!(
+            tokens.iter().map(|token| token.text).collect::<String>(),
+            src
+        );
+
+        let half = tokens.iter().find(|token| token.text == ".5").unwrap();
+        assert_eq!(
+            (half.kind, half.line, half.column),
+            (TokenKind::Number, 3, 20)
+        );
+        assert_eq!(half.offset, src.find(".5").unwrap());
+    }
+
+    #[test]
+    fn unterminated_literals_and_comments() {
+        assert_eq!(
+            kinds("s = \"open\nx /* never closed"),
+            [
+                (TokenKind::Ident, "s"),
+                (TokenKind::Punct, "="),
+                (TokenKind::Unterminated, "\"open"),
+                (TokenKind::Ident, "x"),
+                (TokenKind::Unterminated, "/* never closed"),
+            ]
+        );
+    }
+
+    #[test]
+    fn replaces_only_identifiers() {
+        let src = "T x = T(1); // T\nprintf(\"T\"); Tx = 'T';";
+        assert_eq!(
+            replace_idents(src, |ident| (ident == "T").then(|| "float".to_string())),
+            "float x = float(1); // T\nprintf(\"T\"); Tx = 'T';"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+/// Bump this if the layout of cached files changes.
+const CACHE_VERSION: &str = "custos-macro-cache-v1";
+
+/// The directory compiled kernels are cached in.
+///
+/// Uses `OUT_DIR` if the calling crate has a build script, otherwise `CARGO_TARGET_DIR` or `./target`.
+pub fn cache_dir() -> PathBuf {
+    let base = std::env::var_os("OUT_DIR")
+        .or_else(|| std::env::var_os("CARGO_TARGET_DIR"))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("target"));
+
+    base.join("custos-macro")
+}
+
+/// A stable hash of all inputs that influence a compilation.
+/// Unlike `DefaultHasher`, the result stays the same across Rust releases.
+pub fn cache_key<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(CACHE_VERSION.as_bytes());
+
+    for part in parts {
+        // the length prefix prevents ambiguities, e.g. ("ab", "c") vs ("a", "bc")
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Returns the output of `<compiler> --version`.
+/// The result is memoized, because the proc macro library stays loaded for a whole rustc run.
+pub fn compiler_version(compiler: &str) -> String {
+    static VERSIONS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+    let versions = VERSIONS.get_or_init(Default::default);
+    if let Some(version) = versions.lock().unwrap().get(compiler) {
+        return version.clone();
+    }
+
+    let version = Command::new(compiler)
+        .arg("--version")
+        .output()
+        .map(|out| String::from_utf8_lossy(&out.stdout).into_owned())
+        .unwrap_or_default();
+
+    versions
+        .lock()
+        .unwrap()
+        .insert(compiler.to_string(), version.clone());
+    version
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it afterwards.
+/// Parallel rustc processes therefore either see the whole file or no file at all.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", unique_suffix()));
+    let tmp = PathBuf::from(tmp);
+
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// A scratch directory, which is removed on drop.
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    pub fn new(parent: &Path) -> std::io::Result<ScratchDir> {
+        let dir = parent.join(format!("tmp-{}", unique_suffix()));
+        fs::create_dir_all(&dir)?;
+        Ok(ScratchDir(dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn unique_suffix() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos());
+
+    format!(
+        "{}-{}-{nanos}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
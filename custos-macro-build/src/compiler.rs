@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use crate::options::CudaOptions;
+
+/// The architecture clang compiles for if none is given.
+const DEFAULT_CLANG_ARCH: &str = "sm_52";
+
+/// The compiler that turns CUDA source code into PTX.
+///
+/// Selected with `CUSTOS_CUDA_COMPILER`:
+/// - `nvcc` (default) or a path to an nvcc executable
+/// - `clang` (or e.g. `clang-17`), which compiles with `--cuda-device-only`
+/// - a path to any other executable, which is invoked exactly like nvcc:
+///   `<compiler> -c --ptx [flags] <input.cu> -o <output.ptx>`, and `<compiler> --version`.
+///   It has to write the PTX to the output file and report errors on stderr (nvcc or clang format).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CudaCompiler {
+    Nvcc(String),
+    Clang(String),
+    Custom(String),
+}
+
+impl CudaCompiler {
+    pub fn from_env() -> CudaCompiler {
+        let program = std::env::var("CUSTOS_CUDA_COMPILER")
+            .ok()
+            .filter(|program| !program.is_empty())
+            .unwrap_or_else(|| "nvcc".to_string());
+
+        let file_name = Path::new(&program)
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+
+        if file_name.starts_with("nvcc") {
+            CudaCompiler::Nvcc(program)
+        } else if file_name.starts_with("clang") {
+            CudaCompiler::Clang(program)
+        } else {
+            CudaCompiler::Custom(program)
+        }
+    }
+
+    pub fn program(&self) -> &str {
+        match self {
+            CudaCompiler::Nvcc(program)
+            | CudaCompiler::Clang(program)
+            | CudaCompiler::Custom(program) => program,
+        }
+    }
+
+    /// The name used in diagnostics.
+    pub fn name(&self) -> &str {
+        match self {
+            CudaCompiler::Nvcc(_) => "nvcc",
+            CudaCompiler::Clang(_) => "clang",
+            CudaCompiler::Custom(program) => Path::new(program)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or(program),
+        }
+    }
+
+    /// The flags derived from `options`, excluding input and output files.
+    pub fn flags(&self, options: &CudaOptions) -> Vec<String> {
+        match self {
+            CudaCompiler::Nvcc(_) | CudaCompiler::Custom(_) => options.nvcc_args(),
+            CudaCompiler::Clang(_) => {
+                let mut args = vec![
+                    "--cuda-device-only".to_string(),
+                    "-S".to_string(),
+                    "-x".to_string(),
+                    "cuda".to_string(),
+                    format!(
+                        "--cuda-gpu-arch={}",
+                        options.arch.as_deref().unwrap_or(DEFAULT_CLANG_ARCH)
+                    ),
+                ];
+                // apart from the arch, pre-includes and line info, clang understands the same flags as nvcc
+                for arg in options.nvcc_args() {
+                    if matches!(arg.as_str(), "-c" | "--ptx") || arg.starts_with("-arch=") {
+                        continue;
+                    }
+                    match arg.strip_prefix("--pre-include=") {
+                        Some(header) => args.extend(["-include".to_string(), header.to_string()]),
+                        None if arg == "-lineinfo" => args.push("-gline-tables-only".to_string()),
+                        None => args.push(arg),
+                    }
+                }
+                args
+            }
+        }
+    }
+
+    /// The message for a compiler that could not be started.
+    pub fn spawn_error(&self, err: &std::io::Error) -> String {
+        if err.kind() == std::io::ErrorKind::NotFound {
+            format!(
+                "The CUDA compiler `{}` was not found. \
+                Install the CUDA toolkit or select another compiler with CUSTOS_CUDA_COMPILER \
+                (`nvcc`, `clang` or a path to an executable).",
+                self.program()
+            )
+        } else {
+            format!(
+                "Could not run the CUDA compiler `{}`: {err}",
+                self.program()
+            )
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    cache::{cache_dir, cache_key, compiler_version, write_atomic, ScratchDir},
+    compiler::CudaCompiler,
+    diagnostics::{parse_compiler_output, Diagnostic, Severity},
+    options::CudaOptions,
+    ptx::{parse_ptxas_info, Resources},
+};
+
+/// The architecture `ptxas` assembles for if none is given.
+const DEFAULT_PTXAS_ARCH: &str = "sm_52";
+
+/// The result of a successful kernel compilation.
+pub struct Compiled {
+    pub ptx: String,
+    /// Warnings emitted by the compiler.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+pub enum CompileError {
+    /// The compiler could not be run or its output could not be read.
+    Io(String),
+    /// The compiler rejected the source.
+    Diagnostics(Vec<Diagnostic>),
+}
+
+/// Compiles CUDA source code to PTX.
+///
+/// Results are cached in the `cache_dir` under a key covering the source, the included headers,
+/// the compiler version and the flags. Cache hits do not invoke the compiler at all.
+pub fn compile_ptx(
+    src: &str,
+    includes: &[PathBuf],
+    options: &CudaOptions,
+    compiler: &CudaCompiler,
+) -> Result<Compiled, CompileError> {
+    compile_ptx_in(&cache_dir().join("cuda"), src, includes, options, compiler)
+}
+
+/// [`compile_ptx`] with the cache in `cache_dir`.
+fn compile_ptx_in(
+    cache_dir: &Path,
+    src: &str,
+    includes: &[PathBuf],
+    options: &CudaOptions,
+    compiler: &CudaCompiler,
+) -> Result<Compiled, CompileError> {
+    let flags = compiler.flags(options);
+    let compile = CachedCompile {
+        cache_dir,
+        extensions: ["cu", "ptx"],
+        program: compiler.program(),
+        args: &flags,
+        name: compiler.name(),
+    };
+
+    let (ptx, diagnostics) = compile_cached(
+        &compile,
+        src,
+        includes,
+        |err| compiler.spawn_error(err),
+        |ptx, scratch| stable_file_paths(&String::from_utf8_lossy(&ptx), scratch).into_bytes(),
+    )?;
+    let ptx = String::from_utf8(ptx)
+        .map_err(|err| CompileError::Io(format!("The compiled PTX is not valid UTF-8: {err}")))?;
+
+    Ok(Compiled { ptx, diagnostics })
+}
+
+/// A compiler run of [`compile_cached`].
+pub struct CachedCompile<'a> {
+    /// The directory of the cached outputs, e.g. `cache_dir().join("cuda")`.
+    pub cache_dir: &'a Path,
+    /// The extensions of the input and the output file, e.g. `["cu", "ptx"]`.
+    pub extensions: [&'a str; 2],
+    /// The executable, which is invoked as `<program> [args] <input> -o <output>`.
+    pub program: &'a str,
+    pub args: &'a [String],
+    /// The compiler name used in diagnostics.
+    pub name: &'a str,
+}
+
+/// Compiles `src` and returns the output file and the warnings.
+///
+/// The output is cached under a key of the program, its version, the source, the arguments and the `includes`.
+/// `spawn_error` describes a compiler that could not be started,
+/// `finish` post-processes the output (with the scratch directory it was compiled in) before it is cached.
+pub fn compile_cached(
+    compile: &CachedCompile,
+    src: &str,
+    includes: &[PathBuf],
+    spawn_error: impl FnOnce(&std::io::Error) -> String,
+    finish: impl FnOnce(Vec<u8>, &Path) -> Vec<u8>,
+) -> Result<(Vec<u8>, Vec<Diagnostic>), CompileError> {
+    let CachedCompile {
+        cache_dir,
+        extensions: [input_ext, output_ext],
+        program,
+        args,
+        name,
+    } = compile;
+
+    let headers = includes
+        .iter()
+        .map(|path| std::fs::read(path).unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    let version = compiler_version(program);
+    let key = cache_key(
+        [*program, &version, src]
+            .into_iter()
+            .chain(args.iter().map(String::as_str))
+            .map(str::as_bytes)
+            .chain(headers.iter().map(Vec::as_slice)),
+    );
+
+    let cached_output = cache_dir.join(format!("{key}.{output_ext}"));
+    let cached_log = cache_dir.join(format!("{key}.log"));
+    let input_name = PathBuf::from(format!("{key}.{input_ext}"));
+
+    if let Ok(output) = std::fs::read(&cached_output) {
+        let log = std::fs::read_to_string(&cached_log).unwrap_or_default();
+        let diagnostics = parse_compiler_output(&log, &input_name, src);
+        return Ok((output, diagnostics));
+    }
+
+    let io_err = |err: std::io::Error| {
+        CompileError::Io(format!(
+            "Could not access the kernel cache at {}: {err}",
+            cache_dir.display()
+        ))
+    };
+
+    let scratch = ScratchDir::new(cache_dir).map_err(io_err)?;
+    let input_file_path = scratch.path().join(&input_name);
+    let out_file_path = scratch.path().join(format!("{key}.{output_ext}"));
+
+    std::fs::write(&input_file_path, src.as_bytes()).map_err(io_err)?;
+
+    let out = Command::new(program)
+        .args(args.iter())
+        .arg(&input_file_path)
+        .arg("-o")
+        .arg(&out_file_path)
+        .output()
+        .map_err(|err| CompileError::Io(spawn_error(&err)))?;
+
+    let log = format!(
+        "{}{}",
+        String::from_utf8_lossy(&out.stderr),
+        String::from_utf8_lossy(&out.stdout)
+    );
+    let mut diagnostics = parse_compiler_output(&log, &input_file_path, src);
+
+    if !out.status.success() || has_errors(&diagnostics) {
+        if !has_errors(&diagnostics) {
+            diagnostics.push(Diagnostic::error(format!(
+                "{name} failed ({}): {}",
+                out.status,
+                log.trim()
+            )));
+        }
+        return Err(CompileError::Diagnostics(diagnostics));
+    }
+
+    let output = std::fs::read(&out_file_path).map_err(|err| {
+        CompileError::Io(format!(
+            "Could not read the output of {name} {}: {err}",
+            out_file_path.display()
+        ))
+    })?;
+    let output = finish(output, scratch.path());
+
+    // the log is written first, a present output file implies a complete cache entry
+    write_atomic(&cached_log, log.as_bytes()).map_err(io_err)?;
+    write_atomic(&cached_output, &output).map_err(io_err)?;
+
+    Ok((output, diagnostics))
+}
+
+/// Runs `ptxas -v` on the PTX to find out the registers and shared memory used by each kernel.
+///
+/// The assembler is selected with `CUSTOS_CUDA_PTXAS` (default: `ptxas`). The report is cached like the PTX.
+pub fn resource_usage(
+    ptx: &str,
+    options: &CudaOptions,
+) -> Result<HashMap<String, Resources>, String> {
+    let ptxas = std::env::var("CUSTOS_CUDA_PTXAS")
+        .ok()
+        .filter(|ptxas| !ptxas.is_empty())
+        .unwrap_or_else(|| "ptxas".to_string());
+
+    let arch = options.arch.as_deref().unwrap_or(DEFAULT_PTXAS_ARCH);
+    // ptxas only accepts real architectures
+    let arch = arch.replace("compute_", "sm_");
+
+    let version = compiler_version(&ptxas);
+    let key = cache_key([ptxas.as_str(), &version, &arch, ptx].map(str::as_bytes));
+
+    let cache_dir = cache_dir().join("cuda");
+    let cached_report = cache_dir.join(format!("{key}.ptxas"));
+
+    if let Ok(report) = std::fs::read_to_string(&cached_report) {
+        return Ok(parse_ptxas_info(&report));
+    }
+
+    let io_err = |err: std::io::Error| {
+        format!(
+            "Could not access the kernel cache at {}: {err}",
+            cache_dir.display()
+        )
+    };
+
+    let scratch = ScratchDir::new(&cache_dir).map_err(io_err)?;
+    let input_file_path = scratch.path().join(format!("{key}.ptx"));
+    std::fs::write(&input_file_path, ptx).map_err(io_err)?;
+
+    let out = Command::new(&ptxas)
+        .arg("-v")
+        .arg("--gpu-name")
+        .arg(&arch)
+        .arg(&input_file_path)
+        .arg("-o")
+        .arg(scratch.path().join(format!("{key}.cubin")))
+        .output()
+        .map_err(|err| format!("Could not run `{ptxas}` for `resource_usage`: {err}"))?;
+
+    let report = String::from_utf8_lossy(&out.stderr).into_owned();
+    if !out.status.success() {
+        return Err(format!(
+            "{ptxas} failed ({}): {}",
+            out.status,
+            report.trim()
+        ));
+    }
+
+    write_atomic(&cached_report, report.as_bytes()).map_err(io_err)?;
+    Ok(parse_ptxas_info(&report))
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+}
+
+/// Makes the paths of the `.file` directives independent of the scratch directory and the machine,
+/// so the PTX of a kernel is the same on every build, e.g. for vendoring.
+///
+/// The compiled kernel becomes `<cache key>.cu`, headers are made relative to the crate root or the working directory.
+fn stable_file_paths(ptx: &str, scratch: &Path) -> String {
+    let prefixes = [
+        Some(scratch.to_path_buf()),
+        std::env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from),
+        std::env::current_dir().ok(),
+    ];
+    let prefixes = prefixes
+        .iter()
+        .flatten()
+        .map(|dir| dir.display().to_string())
+        .filter(|dir| !dir.is_empty())
+        .collect::<Vec<_>>();
+
+    let mut stable = String::with_capacity(ptx.len());
+    for line in ptx.split_inclusive('\n') {
+        if !line.trim_start().starts_with(".file") {
+            stable.push_str(line);
+            continue;
+        }
+        // `.file 1 "dir/name"` or, from clang, `.file 1 "dir" "name"`
+        let mut line = line.to_string();
+        for prefix in &prefixes {
+            line = line
+                .replace(&format!("\"{prefix}/"), "\"")
+                .replace(&format!("\"{prefix}\""), "\".\"");
+        }
+        stable.push_str(&line);
+    }
+    stable
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::stable_file_paths;
+
+    #[test]
+    fn file_directives_do_not_contain_the_scratch_dir() {
+        let ptx = concat!(
+            ".file\t1 \"target/custos-macro/cuda/tmp-11314-0-898915351/3f2a.cu\"\n",
+            ".file\t2 \"/usr/include/common.h\"\n",
+            ".file\t3 \"target/custos-macro/cuda/tmp-11314-0-898915351\" \"3f2a.cu\"\n",
+            "\tmov.u32 %r1, \"target/custos-macro/cuda/tmp-11314-0-898915351/\";\n",
+        );
+        let stable = stable_file_paths(
+            ptx,
+            Path::new("target/custos-macro/cuda/tmp-11314-0-898915351"),
+        );
+
+        assert_eq!(
+            stable,
+            concat!(
+                ".file\t1 \"3f2a.cu\"\n",
+                ".file\t2 \"/usr/include/common.h\"\n",
+                ".file\t3 \".\" \"3f2a.cu\"\n",
+                "\tmov.u32 %r1, \"target/custos-macro/cuda/tmp-11314-0-898915351/\";\n",
+            )
+        );
+    }
+
+    /// `compile_ptx` with a shell script as the compiler.
+    #[cfg(unix)]
+    mod stub {
+        use std::{os::unix::fs::PermissionsExt, path::PathBuf, sync::OnceLock};
+
+        use crate::{
+            compiler::CudaCompiler,
+            cuda::{compile_ptx_in, CompileError},
+            diagnostics::Severity,
+            options::CudaOptions,
+        };
+
+        /// A compiler that is invoked like nvcc, logs its arguments to `<stub>.calls`,
+        /// rejects `undefined_symbol` and otherwise writes PTX with a `.file` directive of its input.
+        const STUB: &str = r#"#!/bin/sh
+[ "$1" = "--version" ] && { echo "stub nvcc 1.0"; exit 0; }
+echo "$@" >> "$0.calls"
+for arg; do
+    [ "$prev" = "-o" ] && out="$arg"
+    case "$arg" in *.cu) input="$arg";; esac
+    prev="$arg"
+done
+line=$(grep -n undefined_symbol "$input" | head -n 1 | cut -d: -f1)
+if [ -n "$line" ]; then
+    echo "$input($line): error: identifier \"undefined_symbol\" is undefined" >&2
+    echo "1 error detected in the compilation of \"$input\"." >&2
+    exit 2
+fi
+printf '.file\t1 "%s"\n.visible .entry add(\n\t.param .u64 add_param_0\n)\n{\n}\n' "$input" > "$out"
+"#;
+
+        /// A temporary directory with one stub per test and the cache of the tests.
+        ///
+        /// The stubs are written before any of them is run,
+        /// an executable that is still open for writing in another thread cannot be spawned.
+        fn dir() -> &'static PathBuf {
+            static DIR: OnceLock<PathBuf> = OnceLock::new();
+            DIR.get_or_init(|| {
+                let dir =
+                    std::env::temp_dir().join(format!("custos-macro-build-{}", std::process::id()));
+                // a fresh cache, so the first compilation is not a cache hit of a previous run
+                let _ = std::fs::remove_dir_all(&dir);
+                std::fs::create_dir_all(&dir).unwrap();
+                for name in ["nvcc-cache", "nvcc-error"] {
+                    let path = dir.join(name);
+                    std::fs::write(&path, STUB).unwrap();
+                    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+                        .unwrap();
+                }
+                dir
+            })
+        }
+
+        fn stub(name: &str) -> (CudaCompiler, PathBuf) {
+            let path = dir().join(name);
+            (
+                CudaCompiler::Custom(path.display().to_string()),
+                path.with_extension("calls"),
+            )
+        }
+
+        /// [`compile_ptx_in`] with the cache in the temporary directory instead of `target`.
+        fn compile_ptx(
+            src: &str,
+            options: &CudaOptions,
+            compiler: &CudaCompiler,
+        ) -> Result<crate::cuda::Compiled, CompileError> {
+            compile_ptx_in(&dir().join("cache"), src, &[], options, compiler)
+        }
+
+        #[test]
+        fn compiles_with_a_stub_and_caches_the_ptx() {
+            let (compiler, calls) = stub("nvcc-cache");
+            let src = "extern \"C\" __global__ void add(float* x) { x[0] = BLOCK; }\n";
+            let options = CudaOptions {
+                defines: vec![("BLOCK".to_string(), Some("256".to_string()))],
+                ..CudaOptions::default()
+            };
+
+            let Ok(compiled) = compile_ptx(src, &options, &compiler) else {
+                panic!("the stub rejected the kernel");
+            };
+            assert!(compiled.ptx.contains(".visible .entry add("));
+            assert!(compiled.diagnostics.is_empty());
+            // the scratch directory is not part of the PTX
+            let file = compiled.ptx.lines().next().unwrap();
+            assert!(!file.contains("tmp-"), "{file}");
+            assert!(file.ends_with(".cu\""), "{file}");
+
+            let cached = compile_ptx(src, &options, &compiler).ok().unwrap();
+            assert_eq!(cached.ptx, compiled.ptx);
+
+            let calls = std::fs::read_to_string(calls).unwrap();
+            assert_eq!(calls.lines().count(), 1, "{calls}");
+            assert!(calls.starts_with("-c --ptx -DBLOCK=256 "), "{calls}");
+        }
+
+        #[test]
+        fn reports_the_errors_of_a_stub() {
+            let (compiler, _) = stub("nvcc-error");
+            let src = "__global__ void add(float* x) {\n    x[0] = undefined_symbol;\n}\n";
+
+            let Err(CompileError::Diagnostics(diagnostics)) =
+                compile_ptx(src, &CudaOptions::default(), &compiler)
+            else {
+                panic!("the stub accepted the kernel");
+            };
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics[0].severity, Severity::Error);
+            assert_eq!(diagnostics[0].file, None);
+            assert_eq!(diagnostics[0].line, Some(2));
+            assert_eq!(
+                diagnostics[0].message,
+                "identifier \"undefined_symbol\" is undefined"
+            );
+        }
+    }
+}
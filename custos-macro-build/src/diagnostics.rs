@@ -0,0 +1,256 @@
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+/// A single message of a kernel compiler, e.g. nvcc or clang.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The file the message refers to, if it is not the compiled input file itself (e.g. an included header).
+    pub file: Option<String>,
+    /// 1-based line inside the compiled source.
+    pub line: Option<usize>,
+    /// 1-based column inside the compiled source.
+    pub column: Option<usize>,
+    pub message: String,
+    /// The source line and caret echoed by the compiler, if any.
+    pub context: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            file: None,
+            line: None,
+            column: None,
+            message: message.into(),
+            context: Vec::new(),
+        }
+    }
+}
+
+/// Parses the output of nvcc (`file.cu(12): error: ...`) and of clang-like
+/// compilers (`file.cu:12:5: error: ...`).
+///
+/// Locations are relative to `input_file`, the file the compiler was invoked with.
+/// `source` is the compiled source, which is used to compute the column of EDG style messages from the echoed caret.
+pub fn parse_compiler_output(output: &str, input_file: &Path, source: &str) -> Vec<Diagnostic> {
+    let input_name = input_file.file_name().and_then(|name| name.to_str());
+
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut stray = Vec::new();
+
+    for line in output.lines() {
+        if let Some(header) = parse_nvcc_header(line).or_else(|| parse_clang_header(line)) {
+            let (file, line, column, severity, message) = header;
+            let is_input = input_name.is_some_and(|name| {
+                Path::new(file).file_name().and_then(|file| file.to_str()) == Some(name)
+            });
+
+            diagnostics.push(Diagnostic {
+                severity,
+                file: (!is_input).then(|| file.to_string()),
+                line: Some(line),
+                column,
+                message: message.to_string(),
+                context: Vec::new(),
+            });
+            continue;
+        }
+
+        let Some(last) = diagnostics.last_mut() else {
+            stray.push(line);
+            continue;
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // context lines are indented, everything else (summaries, remarks) ends the previous message
+        if line.starts_with(' ') && last.context.len() < 2 {
+            last.context.push(line.to_string());
+        } else {
+            stray.push(line);
+        }
+    }
+
+    let source_lines = source.lines().collect::<Vec<_>>();
+    for diagnostic in &mut diagnostics {
+        if diagnostic.column.is_some() || diagnostic.file.is_some() {
+            continue;
+        }
+        let Some(line) = diagnostic
+            .line
+            .and_then(|line| line.checked_sub(1))
+            .and_then(|line| source_lines.get(line))
+        else {
+            continue;
+        };
+        diagnostic.column = caret_column(&diagnostic.context, line);
+    }
+
+    // e.g. "nvcc fatal : Unsupported gpu architecture"
+    diagnostics.extend(
+        stray
+            .into_iter()
+            .map(str::trim)
+            .filter(|line| line.contains("fatal") || line.contains("error"))
+            .filter(|line| !line.contains("detected in the compilation of"))
+            .map(Diagnostic::error),
+    );
+
+    diagnostics
+}
+
+type Header<'a> = (&'a str, usize, Option<usize>, Severity, &'a str);
+
+/// `kernel.cu(12): error: identifier "y" is undefined`
+/// `kernel.cu(5): warning #177-D: variable "z" was declared but never referenced`
+fn parse_nvcc_header(line: &str) -> Option<Header<'_>> {
+    let (location, rest) = line.split_once("): ")?;
+    let (file, line_nr) = location.rsplit_once('(')?;
+    let line_nr = line_nr.parse().ok()?;
+
+    let (severity, message) = rest.split_once(": ")?;
+    let severity = parse_severity(severity.split(" #").next()?)?;
+
+    Some((file, line_nr, None, severity, message))
+}
+
+/// `kernel.cu:12:5: error: use of undeclared identifier 'y'`
+fn parse_clang_header(line: &str) -> Option<Header<'_>> {
+    let mut parts = line.splitn(5, ':');
+    let file = parts.next()?;
+    let line_nr = parts.next()?.parse().ok()?;
+    let column = parts.next()?.parse().ok()?;
+    let severity = parse_severity(parts.next()?.trim())?;
+    let message = parts.next()?.trim();
+
+    Some((file, line_nr, Some(column), severity, message))
+}
+
+fn parse_severity(severity: &str) -> Option<Severity> {
+    match severity {
+        "error" | "fatal error" | "catastrophic error" => Some(Severity::Error),
+        "warning" => Some(Severity::Warning),
+        "note" | "remark" => Some(Severity::Note),
+        _ => None,
+    }
+}
+
+/// Computes the column from the echoed source line and the `^` below it.
+/// The echoed line may be indented differently than the original one.
+fn caret_column(context: &[String], source_line: &str) -> Option<usize> {
+    let [echoed, caret] = context else {
+        return None;
+    };
+    let caret = caret.find('^')?;
+
+    let content = source_line.trim_start();
+    let indent = source_line.len() - content.len();
+    let echoed_indent = echoed.find(content.get(..content.len().min(16))?)?;
+
+    let column = (caret + indent).checked_sub(echoed_indent)?;
+    Some(column + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{parse_compiler_output, Severity};
+
+    const INPUT: &str = "target/custos-macro/cuda/tmp-1-0-2/3f2a.cu";
+
+    #[test]
+    fn nvcc_error_with_caret() {
+        let source = "extern \"C\" __global__ void add(float* x) {\n    x[0] = y[0];\n}\n";
+        let output = concat!(
+            "target/custos-macro/cuda/tmp-1-0-2/3f2a.cu(2): error: identifier \"y\" is undefined\n",
+            "      x[0] = y[0];\n",
+            "             ^\n",
+            "\n",
+            "1 error detected in the compilation of \"target/custos-macro/cuda/tmp-1-0-2/3f2a.cu\".\n",
+        );
+
+        let diagnostics = parse_compiler_output(output, Path::new(INPUT), source);
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.file, None);
+        assert_eq!(diagnostic.line, Some(2));
+        // the echoed line is indented by 6 instead of 4 spaces
+        assert_eq!(diagnostic.column, Some(12));
+        assert_eq!(diagnostic.message, "identifier \"y\" is undefined");
+        assert_eq!(diagnostic.context.len(), 2);
+    }
+
+    #[test]
+    fn nvcc_warning_number() {
+        let output =
+            "3f2a.cu(5): warning #177-D: variable \"z\" was declared but never referenced\n";
+        let diagnostics = parse_compiler_output(output, Path::new(INPUT), "");
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].line, Some(5));
+        assert_eq!(
+            diagnostics[0].message,
+            "variable \"z\" was declared but never referenced"
+        );
+    }
+
+    #[test]
+    fn nvcc_error_on_line_zero() {
+        let output = concat!(
+            "3f2a.cu(0): error: unexpected end of file\n",
+            "  x\n",
+            "  ^\n",
+        );
+        let diagnostics = parse_compiler_output(output, Path::new(INPUT), "x\n");
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, Some(0));
+        assert_eq!(diagnostics[0].column, None);
+    }
+
+    #[test]
+    fn clang_diagnostics_in_headers() {
+        let output = concat!(
+            "/tmp/x/3f2a.cu:3:5: error: use of undeclared identifier 'y'\n",
+            "kernels/common.h:7:1: note: expanded from macro 'LOAD'\n",
+        );
+        let diagnostics = parse_compiler_output(output, Path::new(INPUT), "");
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            (
+                diagnostics[0].file.as_deref(),
+                diagnostics[0].line,
+                diagnostics[0].column
+            ),
+            (None, Some(3), Some(5))
+        );
+        assert_eq!(diagnostics[1].severity, Severity::Note);
+        assert_eq!(diagnostics[1].file.as_deref(), Some("kernels/common.h"));
+    }
+
+    #[test]
+    fn fatal_errors_without_location() {
+        let output = "nvcc fatal   : Unsupported gpu architecture 'compute_12'\n";
+        let diagnostics = parse_compiler_output(output, Path::new(INPUT), "");
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].line, None);
+        assert_eq!(
+            diagnostics[0].message,
+            "nvcc fatal   : Unsupported gpu architecture 'compute_12'"
+        );
+    }
+}
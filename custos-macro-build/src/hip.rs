@@ -0,0 +1,104 @@
+use std::path::PathBuf;
+
+use crate::{
+    cache::cache_dir,
+    cuda::{compile_cached, CachedCompile, CompileError},
+    diagnostics::Diagnostic,
+    hipify::hip_header,
+    options::CudaOptions,
+};
+
+/// The environment variables read by `hip!` and [`compile_hip`](crate::compile_hip)
+/// in addition to [`CUDA_ENV_VARS`](crate::options::CUDA_ENV_VARS).
+pub const HIP_ENV_VARS: &[&str] = &["CUSTOS_HIP_COMPILER", "CUSTOS_HIP_ARCH", "CUSTOS_HIP_FLAGS"];
+
+/// The compiler that turns HIP source code into a code object.
+///
+/// Selected with `CUSTOS_HIP_COMPILER`, e.g. `hipcc` or a path to it.
+/// If it is not set, `hip!` embeds the translated source instead.
+pub struct HipCompiler {
+    program: String,
+    arch: Option<String>,
+    flags: Vec<String>,
+}
+
+impl HipCompiler {
+    pub fn from_env(options: &CudaOptions) -> Option<HipCompiler> {
+        let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let program = var("CUSTOS_HIP_COMPILER")?;
+
+        // `arch` is used if it names an AMD architecture, `sm_80` is meant for the CUDA target
+        let arch = options
+            .arch
+            .clone()
+            .filter(|arch| arch.starts_with("gfx"))
+            .or_else(|| var("CUSTOS_HIP_ARCH"));
+        let flags = var("CUSTOS_HIP_FLAGS")
+            .map(|flags| flags.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+
+        Some(HipCompiler {
+            program,
+            arch,
+            flags,
+        })
+    }
+
+    /// `--genco` and the options, translated from their nvcc form.
+    fn args(&self, options: &CudaOptions) -> Vec<String> {
+        let mut args = vec!["--genco".to_string()];
+        if let Some(arch) = &self.arch {
+            args.push(format!("--offload-arch={arch}"));
+        }
+
+        for arg in options.nvcc_args() {
+            if matches!(arg.as_str(), "-c" | "--ptx" | "-lineinfo") || arg.starts_with("-arch=") {
+                continue;
+            }
+            match arg.strip_prefix("--pre-include=") {
+                Some(header) => args.extend([
+                    "-include".to_string(),
+                    hip_header(header).unwrap_or(header).to_string(),
+                ]),
+                None => args.push(arg),
+            }
+        }
+        args.extend(self.flags.iter().cloned());
+        args
+    }
+}
+
+/// Compiles HIP source code to a code object, cached like the PTX of `cuda!`.
+pub fn compile_code_object(
+    src: &str,
+    includes: &[PathBuf],
+    options: &CudaOptions,
+    compiler: &HipCompiler,
+) -> Result<(Vec<u8>, Vec<Diagnostic>), CompileError> {
+    let args = compiler.args(options);
+    let compile = CachedCompile {
+        cache_dir: &cache_dir().join("hip"),
+        extensions: ["hip", "hsaco"],
+        program: &compiler.program,
+        args: &args,
+        name: &compiler.program,
+    };
+
+    compile_cached(
+        &compile,
+        src,
+        includes,
+        |err| match err.kind() {
+            std::io::ErrorKind::NotFound => format!(
+                "The HIP compiler `{}` was not found. \
+                Install ROCm or unset CUSTOS_HIP_COMPILER to embed the translated source.",
+                compiler.program
+            ),
+            _ => format!(
+                "Could not run the HIP compiler `{}`: {err}",
+                compiler.program
+            ),
+        },
+        |bin, _| bin,
+    )
+}
@@ -0,0 +1,173 @@
+use crate::c_lexer::replace_idents;
+
+/// CUDA headers and their HIP counterparts.
+const HEADERS: &[(&str, &str)] = &[
+    ("cuda.h", "hip/hip_runtime.h"),
+    ("cuda_runtime.h", "hip/hip_runtime.h"),
+    ("cuda_runtime_api.h", "hip/hip_runtime_api.h"),
+    ("cuda_fp16.h", "hip/hip_fp16.h"),
+    ("cuda_bf16.h", "hip/hip_bf16.h"),
+    ("cooperative_groups.h", "hip/hip_cooperative_groups.h"),
+    ("curand_kernel.h", "hiprand/hiprand_kernel.h"),
+    ("cub/cub.cuh", "hipcub/hipcub.hpp"),
+];
+
+/// Identifiers that are not covered by the `cuda` -> `hip` prefix rule.
+const IDENTS: &[(&str, &str)] = &[
+    ("__CUDACC__", "__HIPCC__"),
+    ("__CUDA_ARCH__", "__HIP_DEVICE_COMPILE__"),
+    ("__nv_bfloat16", "__hip_bfloat16"),
+    ("__nv_bfloat162", "__hip_bfloat162"),
+    ("cub", "hipcub"),
+    ("CUDART_INF_F", "HIP_INF_F"),
+    ("CUDART_NAN_F", "HIP_NAN_F"),
+    ("CUDART_INF", "HIP_INF"),
+    ("CUDART_NAN", "HIP_NAN"),
+];
+
+/// Translates CUDA source code to HIP, like a minimal `hipify-perl`:
+///
+/// - `#include <cuda_fp16.h>` -> `#include <hip/hip_fp16.h>`
+/// - `cudaMemcpy`, `cudaStream_t` -> `hipMemcpy`, `hipStream_t`
+/// - `curandState`, `curand_uniform`, `cublasHandle_t` -> `hiprandState`, `hiprand_uniform`, `hipblasHandle_t`
+/// - `__CUDA_ARCH__`, `__nv_bfloat16` and other macros and types
+///
+/// Comments and string literals are left untouched and every line stays on its line,
+/// so diagnostics of the HIP compiler map back to the CUDA source.
+pub fn hipify(src: &str) -> String {
+    let src = src
+        .split_inclusive('\n')
+        .map(translate_include)
+        .collect::<String>();
+
+    replace_idents(&src, translate_ident)
+}
+
+/// Returns the HIP header for a CUDA header, e.g. `hip/hip_fp16.h` for `cuda_fp16.h`.
+pub fn hip_header(header: &str) -> Option<&'static str> {
+    HEADERS
+        .iter()
+        .find(|(cuda, _)| *cuda == header)
+        .map(|(_, hip)| *hip)
+}
+
+fn translate_include(line: &str) -> String {
+    let Some(directive) = line.trim_start().strip_prefix('#') else {
+        return line.to_string();
+    };
+    let Some(path) = directive.trim_start().strip_prefix("include") else {
+        return line.to_string();
+    };
+
+    let path = path.trim();
+    let header = path
+        .strip_prefix('<')
+        .and_then(|path| path.strip_suffix('>'))
+        .or_else(|| {
+            path.strip_prefix('"')
+                .and_then(|path| path.strip_suffix('"'))
+        });
+
+    match header.and_then(hip_header) {
+        Some(hip) => line.replacen(header.unwrap_or_default(), hip, 1),
+        None => line.to_string(),
+    }
+}
+
+fn translate_ident(ident: &str) -> Option<String> {
+    if let Some((_, hip)) = IDENTS.iter().find(|(cuda, _)| *cuda == ident) {
+        return Some(hip.to_string());
+    }
+
+    // cudaMalloc, curandState, cublasSgemm, cufftExecC2C, ...
+    for (cuda, hip) in [
+        ("cuda", "hip"),
+        ("curand", "hiprand"),
+        ("cublas", "hipblas"),
+        ("cufft", "hipfft"),
+    ] {
+        let Some(rest) = ident.strip_prefix(cuda) else {
+            continue;
+        };
+        // `curand_uniform` is translated, `cuda_fp16` or a user's `cuda_helper` are left alone
+        if rest.starts_with(|c: char| c.is_ascii_uppercase() || (c == '_' && cuda != "cuda")) {
+            return Some(format!("{hip}{rest}"));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hip_header, hipify};
+
+    #[test]
+    fn renames_runtime_api_and_types() {
+        let src =
+            "cudaStream_t stream;\ncudaMemcpyAsync(dst, src, n, cudaMemcpyDeviceToHost, stream);\n";
+        assert_eq!(
+            hipify(src),
+            "hipStream_t stream;\nhipMemcpyAsync(dst, src, n, hipMemcpyDeviceToHost, stream);\n"
+        );
+    }
+
+    #[test]
+    fn renames_libraries_macros_and_types() {
+        assert_eq!(
+            hipify("curandState s; float x = curand_uniform(&s); cublasHandle_t h;"),
+            "hiprandState s; float x = hiprand_uniform(&s); hipblasHandle_t h;"
+        );
+        assert_eq!(
+            hipify("#ifdef __CUDA_ARCH__\n__nv_bfloat16 x = CUDART_INF_F;\n#endif\n"),
+            "#ifdef __HIP_DEVICE_COMPILE__\n__hip_bfloat16 x = HIP_INF_F;\n#endif\n"
+        );
+        assert_eq!(
+            hipify("cub::BlockReduce<float, 256>"),
+            "hipcub::BlockReduce<float, 256>"
+        );
+    }
+
+    #[test]
+    fn keeps_user_identifiers_with_cuda_prefix() {
+        let src = "int cuda_helper(int x); int cudalike; int mycudaMalloc;";
+        assert_eq!(hipify(src), src);
+    }
+
+    #[test]
+    fn translates_includes() {
+        assert_eq!(
+            hipify("#include <cuda_fp16.h>\n#  include \"cuda_runtime.h\"\n#include <local.h>\n"),
+            "#include <hip/hip_fp16.h>\n#  include \"hip/hip_runtime.h\"\n#include <local.h>\n"
+        );
+        assert_eq!(hip_header("cub/cub.cuh"), Some("hipcub/hipcub.hpp"));
+        assert_eq!(hip_header("local.h"), None);
+    }
+
+    #[test]
+    fn keeps_triple_chevron_launches() {
+        // hipcc understands `<<<...>>>`, only the identifiers inside are translated
+        assert_eq!(
+            hipify("add<<<grid, block, 0, cudaStreamPerThread>>>(a, b, n);"),
+            "add<<<grid, block, 0, hipStreamPerThread>>>(a, b, n);"
+        );
+    }
+
+    #[test]
+    fn keeps_strings_and_comments() {
+        let src = concat!(
+            "// cudaMalloc is not called here\n",
+            "/* cudaFree(ptr);\n   cudaMemcpy */\n",
+            "printf(\"cudaMemcpy failed\\n\");\n",
+            "char c = 'c'; cudaFree(ptr);\n",
+        );
+        assert_eq!(
+            hipify(src),
+            concat!(
+                "// cudaMalloc is not called here\n",
+                "/* cudaFree(ptr);\n   cudaMemcpy */\n",
+                "printf(\"cudaMemcpy failed\\n\");\n",
+                "char c = 'c'; hipFree(ptr);\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use std::path::{Path, PathBuf};
+
+/// Finds all local headers (`#include "..."`) of `src`, including the ones included by headers.
+///
+/// Headers are looked up relative to the including file first, then in `include_dirs`.
+/// Headers that cannot be found are skipped, the compiler reports them.
+pub fn resolve_includes(
+    src: &str,
+    base_dir: Option<&Path>,
+    include_dirs: &[PathBuf],
+) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    collect_includes(src, base_dir, include_dirs, &mut found);
+    found
+}
+
+fn collect_includes(
+    src: &str,
+    base_dir: Option<&Path>,
+    include_dirs: &[PathBuf],
+    found: &mut Vec<PathBuf>,
+) {
+    for header in local_includes(src) {
+        let Some(path) = base_dir
+            .into_iter()
+            .chain(include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(header))
+            .find(|path| path.is_file())
+        else {
+            continue;
+        };
+
+        let path = path.canonicalize().unwrap_or(path);
+        if found.contains(&path) {
+            continue;
+        }
+
+        let Ok(header_src) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        found.push(path.clone());
+        collect_includes(&header_src, path.parent(), include_dirs, found);
+    }
+}
+
+/// The paths of all `#include "..."` directives.
+fn local_includes(src: &str) -> impl Iterator<Item = &str> {
+    src.lines().filter_map(|line| {
+        let directive = line.trim_start().strip_prefix('#')?.trim_start();
+        let path = directive.strip_prefix("include")?.trim_start();
+        let path = path.strip_prefix('"')?;
+        Some(&path[..path.find('"')?])
+    })
+}
@@ -0,0 +1,63 @@
+//! Compiles and checks custos kernels in build scripts.
+//!
+//! The proc macros of custos-macro use the same pipeline: compilers are configured with the same
+//! `CUSTOS_*` environment variables, and compiled kernels share the cache.
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() -> Result<(), custos_macro_build::BuildError> {
+//!     custos_macro_build::compile_cuda_dir("kernels")?;
+//!     custos_macro_build::compile_hip("kernels/add.cu")?;
+//!     custos_macro_build::validate_opencl("kernels/add.cl")?;
+//!     custos_macro_build::compile_wgsl("shaders/fill.wgsl")?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The outputs are written to `OUT_DIR`, e.g. `include_str!(concat!(env!("OUT_DIR"), "/kernels/add.ptx"))`.
+
+mod build_script;
+mod cache;
+mod reload;
+
+// the pipeline of the custos-macro proc macros, not a stable API
+#[doc(hidden)]
+pub mod c_lexer;
+#[doc(hidden)]
+pub mod compiler;
+#[doc(hidden)]
+pub mod cuda;
+#[doc(hidden)]
+pub mod diagnostics;
+#[doc(hidden)]
+pub mod hip;
+#[doc(hidden)]
+pub mod hipify;
+#[doc(hidden)]
+pub mod includes;
+#[doc(hidden)]
+pub mod opencl;
+#[doc(hidden)]
+pub mod options;
+#[doc(hidden)]
+pub mod ptx;
+#[cfg(feature = "wgsl")]
+#[doc(hidden)]
+pub mod shader;
+#[doc(hidden)]
+pub mod signatures;
+#[doc(hidden)]
+pub mod vendor;
+
+#[cfg(feature = "wgsl")]
+pub use build_script::compile_wgsl;
+pub use build_script::{
+    compile_cuda, compile_cuda_dir, compile_hip, compile_hip_dir, find_kernels, validate_opencl,
+    BuildError,
+};
+pub use diagnostics::{Diagnostic, Severity};
+#[cfg(feature = "wgsl")]
+#[doc(hidden)]
+pub use naga;
+pub use options::CudaOptions;
+pub use reload::{HotReload, Reload};
@@ -0,0 +1,307 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    c_lexer::{tokenize, TokenKind},
+    cache::{cache_dir, cache_key, compiler_version, write_atomic, ScratchDir},
+    cuda::has_errors,
+    diagnostics::{parse_compiler_output, Diagnostic},
+};
+
+/// The environment variables read when checking or compiling OpenCL kernels.
+pub const OPENCL_ENV_VARS: &[&str] = &["CUSTOS_OPENCL_CLANG", "CUSTOS_OPENCL_FLAGS"];
+
+/// A clang that checks OpenCL C, selected with `CUSTOS_OPENCL_CLANG`.
+/// `CUSTOS_OPENCL_FLAGS` adds flags, e.g. `-D N=256`.
+pub struct Clang {
+    pub program: String,
+    pub flags: Vec<String>,
+}
+
+impl Clang {
+    pub fn from_env() -> Option<Clang> {
+        let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        Some(Clang {
+            program: var("CUSTOS_OPENCL_CLANG")?,
+            flags: var("CUSTOS_OPENCL_FLAGS")
+                .map(|flags| flags.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// The first word of a SPIR-V module.
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+/// What clang produces from a kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClangOutput {
+    /// Only checks the syntax and types.
+    SyntaxOnly,
+    /// A SPIR-V module for `clCreateProgramWithIL`.
+    Spirv,
+}
+
+/// Checks OpenCL C 2.0 source code with clang or compiles it to SPIR-V (`--target=spirv64`,
+/// which needs clang 15 or newer and `llvm-spirv` next to it).
+///
+/// Returns the SPIR-V, if it was requested and the compilation succeeded, and the diagnostics. Both are cached.
+pub fn run_clang(
+    src: &str,
+    include_dir: Option<&Path>,
+    clang: &Clang,
+    output: ClangOutput,
+) -> Result<(Option<Vec<u8>>, Vec<Diagnostic>), String> {
+    let mut args = [
+        "-x",
+        "cl",
+        "-cl-std=CL2.0",
+        "-Xclang",
+        "-finclude-default-header",
+    ]
+    .map(String::from)
+    .to_vec();
+    match output {
+        ClangOutput::SyntaxOnly => args.push("-fsyntax-only".to_string()),
+        ClangOutput::Spirv => args.extend(["--target=spirv64", "-c"].map(String::from)),
+    }
+    if let Some(dir) = include_dir {
+        args.push(format!("-I{}", dir.display()));
+    }
+    args.extend(clang.flags.iter().cloned());
+
+    let version = compiler_version(&clang.program);
+    let key = cache_key(
+        [clang.program.as_str(), &version, src]
+            .into_iter()
+            .chain(args.iter().map(String::as_str))
+            .map(str::as_bytes),
+    );
+
+    let cache_dir = cache_dir().join("opencl");
+    let cached_log = cache_dir.join(format!("{key}.log"));
+    let cached_spv = cache_dir.join(format!("{key}.spv"));
+    let input_name = PathBuf::from(format!("{key}.cl"));
+    if let Ok(log) = std::fs::read_to_string(&cached_log) {
+        let diagnostics = parse_compiler_output(&log, &input_name, src);
+        match output {
+            ClangOutput::SyntaxOnly => return Ok((None, diagnostics)),
+            ClangOutput::Spirv => {
+                if let Ok(spv) = std::fs::read(&cached_spv) {
+                    return Ok((Some(checked_spirv(spv, &clang.program)?), diagnostics));
+                }
+            }
+        }
+    }
+
+    let io_err = |err: std::io::Error| {
+        format!(
+            "Could not access the kernel cache at {}: {err}",
+            cache_dir.display()
+        )
+    };
+    let scratch = ScratchDir::new(&cache_dir).map_err(io_err)?;
+    let input_file_path = scratch.path().join(&input_name);
+    let out_file_path = scratch.path().join(format!("{key}.spv"));
+    std::fs::write(&input_file_path, src).map_err(io_err)?;
+
+    let mut command = Command::new(&clang.program);
+    command.args(&args).arg(&input_file_path);
+    if output == ClangOutput::Spirv {
+        command.arg("-o").arg(&out_file_path);
+    }
+    let out = command.output().map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => format!(
+            "`{}` was not found. Install clang or unset CUSTOS_OPENCL_CLANG to skip the syntax check.",
+            clang.program
+        ),
+        _ => format!("Could not run `{}`: {err}", clang.program),
+    })?;
+
+    let log = format!(
+        "{}{}",
+        String::from_utf8_lossy(&out.stderr),
+        String::from_utf8_lossy(&out.stdout)
+    );
+    let mut diagnostics = parse_compiler_output(&log, &input_file_path, src);
+    if !out.status.success() && !has_errors(&diagnostics) {
+        diagnostics.push(Diagnostic::error(format!(
+            "{} failed ({}): {}",
+            clang.program,
+            out.status,
+            log.trim()
+        )));
+        return Ok((None, diagnostics));
+    }
+
+    // the log is written first, a present .spv file implies a complete cache entry
+    write_atomic(&cached_log, log.as_bytes()).map_err(io_err)?;
+    if output == ClangOutput::SyntaxOnly || !out.status.success() {
+        return Ok((None, diagnostics));
+    }
+
+    let spv = std::fs::read(&out_file_path).map_err(|err| {
+        format!(
+            "Could not read the SPIR-V {}: {err}",
+            out_file_path.display()
+        )
+    })?;
+    let spv = checked_spirv(spv, &clang.program)?;
+    write_atomic(&cached_spv, &spv).map_err(io_err)?;
+    Ok((Some(spv), diagnostics))
+}
+
+/// Returns an error if `spv` does not start with the SPIR-V magic number,
+/// e.g. SPIR LLVM bitcode, which `clCreateProgramWithIL` rejects.
+fn checked_spirv(spv: Vec<u8>, program: &str) -> Result<Vec<u8>, String> {
+    let magic = spv
+        .get(..4)
+        .and_then(|magic| <[u8; 4]>::try_from(magic).ok());
+    match magic {
+        Some(magic)
+            if u32::from_le_bytes(magic) == SPIRV_MAGIC
+                || u32::from_be_bytes(magic) == SPIRV_MAGIC =>
+        {
+            Ok(spv)
+        }
+        _ => Err(format!(
+            "`{program}` did not produce a SPIR-V module (missing the magic number {SPIRV_MAGIC:#010x}). \
+             Compiling OpenCL C to SPIR-V needs clang 15 or newer with `llvm-spirv`."
+        )),
+    }
+}
+
+/// Checks OpenCL C source code lexically: unterminated literals and comments and unbalanced brackets.
+pub fn validate_opencl(src: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut open = Vec::new();
+
+    for token in tokenize(src) {
+        match (token.kind, token.text) {
+            (TokenKind::Unterminated, text) => {
+                let what = if text.starts_with("/*") {
+                    "comment"
+                } else {
+                    "literal"
+                };
+                diagnostics.push(located(
+                    format!("unterminated {what}"),
+                    token.line,
+                    token.column,
+                ));
+            }
+            (TokenKind::Punct, "(" | "[" | "{") => open.push(token),
+            (TokenKind::Punct, close @ (")" | "]" | "}")) => {
+                let expected = match close {
+                    ")" => "(",
+                    "]" => "[",
+                    _ => "{",
+                };
+                match open.pop() {
+                    Some(opening) if opening.text == expected => {}
+                    Some(opening) => diagnostics.push(located(
+                        format!(
+                            "mismatched closing delimiter `{close}` for `{}` opened at line {}",
+                            opening.text, opening.line
+                        ),
+                        token.line,
+                        token.column,
+                    )),
+                    None => diagnostics.push(located(
+                        format!("unexpected closing delimiter `{close}`"),
+                        token.line,
+                        token.column,
+                    )),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    diagnostics.extend(open.into_iter().map(|token| {
+        located(
+            format!("unclosed delimiter `{}`", token.text),
+            token.line,
+            token.column,
+        )
+    }));
+
+    diagnostics
+}
+
+fn located(message: String, line: usize, column: usize) -> Diagnostic {
+    Diagnostic {
+        line: Some(line),
+        column: Some(column),
+        ..Diagnostic::error(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checked_spirv, validate_opencl};
+
+    /// The message, line and column of every diagnostic.
+    fn lexical(src: &str) -> Vec<(String, usize, usize)> {
+        validate_opencl(src)
+            .into_iter()
+            .map(|diagnostic| {
+                (
+                    diagnostic.message,
+                    diagnostic.line.unwrap(),
+                    diagnostic.column.unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_source_has_no_diagnostics() {
+        let src =
+            "// ) in a comment\n__kernel void add(__global float* x) { x[0] = ')'; /* } */ }\n";
+        assert!(validate_opencl(src).is_empty());
+    }
+
+    #[test]
+    fn unbalanced_delimiters() {
+        assert_eq!(
+            lexical("__kernel void add(__global float* x) {\n    x[0 = 1.0f;\n}\n"),
+            [
+                (
+                    "mismatched closing delimiter `}` for `[` opened at line 2".to_string(),
+                    3,
+                    1
+                ),
+                ("unclosed delimiter `{`".to_string(), 1, 38),
+            ]
+        );
+        assert_eq!(
+            lexical("void f() {}\n}"),
+            [("unexpected closing delimiter `}`".to_string(), 2, 1)]
+        );
+    }
+
+    #[test]
+    fn unterminated_literals_and_comments() {
+        assert_eq!(
+            lexical("__kernel void f() {\n    char* s = \"abc;\n}\n/* open"),
+            [
+                ("unterminated literal".to_string(), 2, 15),
+                ("unterminated comment".to_string(), 4, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn spirv_needs_the_magic_number() {
+        let spirv = [0x03, 0x02, 0x23, 0x07, 0x00, 0x00, 0x01, 0x00].to_vec();
+        assert_eq!(checked_spirv(spirv.clone(), "clang"), Ok(spirv));
+
+        // SPIR LLVM bitcode of `-target spir64`
+        let bitcode = b"BC\xc0\xde\x35\x14\x00\x00".to_vec();
+        assert!(checked_spirv(bitcode, "clang").is_err());
+        assert!(checked_spirv(Vec::new(), "clang").is_err());
+    }
+}
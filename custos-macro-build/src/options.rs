@@ -0,0 +1,148 @@
+use std::path::{Path, PathBuf};
+
+/// Compiler settings of a kernel.
+///
+/// Crate-wide defaults are read from the environment:
+/// `CUSTOS_CUDA_ARCH`, `CUSTOS_CUDA_OPT_LEVEL`, `CUSTOS_CUDA_DEFINES` (e.g. `"BLOCK=256 USE_FAST"`),
+/// `CUSTOS_CUDA_INCLUDE_DIRS` (a path list) and `CUSTOS_CUDA_FLAGS` (whitespace separated).
+/// Options passed to the macro override `arch` and `opt_level`, the other ones are appended.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CudaOptions {
+    pub arch: Option<String>,
+    pub opt_level: Option<String>,
+    pub defines: Vec<(String, Option<String>)>,
+    pub include_dirs: Vec<PathBuf>,
+    pub flags: Vec<String>,
+}
+
+impl CudaOptions {
+    pub fn from_env() -> CudaOptions {
+        let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let split = |name| {
+            var(name)
+                .map(|value: String| value.split_whitespace().map(String::from).collect())
+                .unwrap_or_else(Vec::new)
+        };
+
+        CudaOptions {
+            arch: var("CUSTOS_CUDA_ARCH"),
+            opt_level: var("CUSTOS_CUDA_OPT_LEVEL"),
+            defines: split("CUSTOS_CUDA_DEFINES")
+                .into_iter()
+                .map(|define| match define.split_once('=') {
+                    Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                    None => (define, None),
+                })
+                .collect(),
+            include_dirs: std::env::var_os("CUSTOS_CUDA_INCLUDE_DIRS")
+                .map(|dirs| std::env::split_paths(&dirs).map(resolve_path).collect())
+                .unwrap_or_default(),
+            flags: split("CUSTOS_CUDA_FLAGS"),
+        }
+    }
+
+    /// The command line arguments for nvcc, excluding input and output files.
+    pub fn nvcc_args(&self) -> Vec<String> {
+        let mut args = vec!["-c".to_string(), "--ptx".to_string()];
+
+        if let Some(arch) = &self.arch {
+            args.push(format!("-arch={arch}"));
+        }
+        if let Some(opt_level) = &self.opt_level {
+            args.push(format!("-O{opt_level}"));
+        }
+        args.extend(self.defines.iter().map(|(name, value)| match value {
+            Some(value) => format!("-D{name}={value}"),
+            None => format!("-D{name}"),
+        }));
+        args.extend(
+            self.include_dirs
+                .iter()
+                .map(|dir| format!("-I{}", dir.display())),
+        );
+        args.extend(self.flags.iter().cloned());
+        args
+    }
+
+    /// `#define` and `#include` directives for the `defines` and pre-included headers,
+    /// for compilers that only receive the source.
+    pub fn source_preamble(&self) -> String {
+        let defines = self.defines.iter().map(|(name, value)| match value {
+            Some(value) => format!("#define {name} {value}\n"),
+            None => format!("#define {name}\n"),
+        });
+        let includes = self
+            .flags
+            .iter()
+            .filter_map(|flag| flag.strip_prefix("--pre-include="))
+            .map(|header| format!("#include <{header}>\n"));
+
+        defines.chain(includes).collect()
+    }
+
+    /// The options for NVRTC.
+    /// NVRTC has no optimization level, therefore `opt_level` is ignored.
+    /// Include directories have to exist on the machine that compiles the kernel.
+    pub fn nvrtc_args(&self) -> Vec<String> {
+        self.nvcc_args()
+            .into_iter()
+            .filter(|arg| !matches!(arg.as_str(), "-c" | "--ptx") && !arg.starts_with("-O"))
+            .map(|arg| match arg.strip_prefix("-arch=") {
+                Some(arch) => format!("--gpu-architecture={arch}"),
+                None => arg,
+            })
+            .collect()
+    }
+}
+
+/// What `cuda!` embeds into the binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CudaMode {
+    /// Compiles the kernel to PTX at build time.
+    Ptx,
+    /// Embeds the source and the options, the custos CUDA backend compiles it at runtime with NVRTC.
+    Runtime,
+}
+
+impl CudaMode {
+    /// Reads `CUSTOS_CUDA_MODE` (`ptx` or `runtime`).
+    /// If it is not set, the `runtime-compile` feature selects the runtime mode.
+    pub fn from_env() -> Result<CudaMode, String> {
+        match std::env::var("CUSTOS_CUDA_MODE").as_deref() {
+            Ok("ptx") => Ok(CudaMode::Ptx),
+            Ok("runtime") => Ok(CudaMode::Runtime),
+            Ok("") | Err(_) if cfg!(feature = "runtime-compile") => Ok(CudaMode::Runtime),
+            Ok("") | Err(_) => Ok(CudaMode::Ptx),
+            Ok(mode) => Err(format!(
+                "Unknown CUSTOS_CUDA_MODE `{mode}`, expected `ptx` or `runtime`."
+            )),
+        }
+    }
+}
+
+/// The environment variables read when compiling CUDA kernels.
+pub const CUDA_ENV_VARS: &[&str] = &[
+    "CUSTOS_CUDA_ARCH",
+    "CUSTOS_CUDA_OPT_LEVEL",
+    "CUSTOS_CUDA_DEFINES",
+    "CUSTOS_CUDA_INCLUDE_DIRS",
+    "CUSTOS_CUDA_FLAGS",
+    "CUSTOS_CUDA_COMPILER",
+    "CUSTOS_CUDA_MODE",
+    "CUSTOS_CUDA_PTXAS",
+    "CUSTOS_CUDA_VENDOR",
+    "CUSTOS_CUDA_VENDOR_DIR",
+];
+
+/// Resolves a path relative to the manifest directory of the crate that is being built,
+/// i.e. the crate that invokes a macro or runs a build script.
+pub fn resolve_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+    std::env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(path)
+}
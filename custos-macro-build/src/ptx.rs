@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+
+/// A kernel entry point declared in PTX with `.entry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    /// The PTX types of the parameters without the leading dot, e.g. `u64` or `b8[16]`.
+    pub params: Vec<String>,
+}
+
+/// The resource usage of a kernel as reported by `ptxas -v`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Resources {
+    pub registers: u32,
+    pub shared_mem_bytes: u32,
+}
+
+/// Parses all `.entry` declarations, e.g.
+///
+/// ```text
+/// .visible .entry add(
+///     .param .u64 add_param_0,
+///     .param .u32 add_param_1
+/// )
+/// ```
+pub fn parse_entries(ptx: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut rest = ptx;
+
+    while let Some(start) = rest.find(".entry") {
+        rest = &rest[start + ".entry".len()..];
+
+        let Some(open) = rest.find('(') else {
+            break;
+        };
+        let name = rest[..open].trim();
+        let close = rest[open..]
+            .find(')')
+            .map_or(rest.len(), |close| open + close);
+
+        let params = rest[open + 1..close]
+            .split(',')
+            .filter_map(|param| {
+                // e.g. `.param .align 8 .b8 add_param_0[16]`
+                let mut words = param.split_whitespace();
+                let mut ty = None;
+                while let Some(word) = words.next() {
+                    match word {
+                        ".param" | ".ptr" | ".global" | ".const" | ".local" | ".shared" => {}
+                        ".align" => {
+                            words.next();
+                        }
+                        word if word.starts_with('.') => ty = Some(&word[1..]),
+                        name => {
+                            let ty = ty?;
+                            return Some(match name.find('[') {
+                                Some(array) => format!("{ty}{}", &name[array..]),
+                                None => ty.to_string(),
+                            });
+                        }
+                    }
+                }
+                None
+            })
+            .collect();
+
+        if !name.is_empty() {
+            entries.push(Entry {
+                name: name.to_string(),
+                params,
+            });
+        }
+        rest = &rest[close..];
+    }
+
+    entries
+}
+
+/// A `.loc` directive: the PTX line and the source line it was generated from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    /// The line of the PTX (1-based) from which on the following instructions belong to `line`.
+    pub ptx_line: usize,
+    /// The `.entry` or `.func` the line is part of.
+    pub function: String,
+    /// The source file of the `.file` directive, `None` for the compiled kernel itself
+    /// (the `<cache key>.cu` file of [`compile_ptx`](crate::cuda::compile_ptx)).
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Parses the `.loc` directives of PTX compiled with `-lineinfo`, e.g.
+///
+/// ```text
+/// .file 1 "3f2a....cu"
+/// .visible .entry add(
+/// ...
+/// .loc 1 4 9
+/// ```
+///
+/// Consecutive directives of the same source line are merged.
+pub fn parse_source_map(ptx: &str) -> Vec<SourceLine> {
+    let mut files = HashMap::new();
+    let mut function = String::new();
+    let mut source_map: Vec<SourceLine> = Vec::new();
+
+    for (idx, line) in ptx.lines().enumerate() {
+        let line = line.trim();
+
+        if let Some(file) = line.strip_prefix(".file") {
+            // `.file 1 "path"`, clang may split it into `"dir", "name"` and append a timestamp and size
+            let mut parts = file.split('"');
+            let Some(Ok(index)) = parts.next().map(|index| index.trim().parse::<usize>()) else {
+                continue;
+            };
+            let quoted = parts.step_by(2).collect::<Vec<_>>();
+            let path = match quoted.as_slice() {
+                [dir, name, ..] if !name.is_empty() && !name.starts_with('/') => {
+                    format!("{}/{name}", dir.trim_end_matches('/'))
+                }
+                [path, ..] => path.to_string(),
+                [] => continue,
+            };
+            files.insert(index, path);
+            continue;
+        }
+
+        let is_definition = !line.ends_with(';') && !line.starts_with(".extern");
+        if is_definition && (line.contains(".entry") || line.contains(".func")) {
+            // `.visible .func  (.param .b32 func_retval0) foo(`
+            let head = line.trim_end_matches('(');
+            let head = head.rsplit_once('(').map_or(head, |(head, _)| head);
+            function = head
+                .split_whitespace()
+                .next_back()
+                .unwrap_or_default()
+                .to_string();
+            continue;
+        }
+
+        let Some(loc) = line.strip_prefix(".loc") else {
+            continue;
+        };
+        let mut numbers = loc
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|part| !part.is_empty())
+            .map(str::parse::<usize>);
+        let (Some(Ok(file)), Some(Ok(source_line)), Some(Ok(column))) =
+            (numbers.next(), numbers.next(), numbers.next())
+        else {
+            continue;
+        };
+        if source_line == 0 {
+            continue;
+        }
+
+        let file = files
+            .get(&file)
+            .filter(|path| !is_compiled_kernel(path))
+            .cloned();
+        if source_map.last().is_some_and(|last| {
+            last.function == function && last.file == file && last.line == source_line
+        }) {
+            continue;
+        }
+        source_map.push(SourceLine {
+            ptx_line: idx + 1,
+            function: function.clone(),
+            file,
+            line: source_line,
+            column,
+        });
+    }
+
+    source_map
+}
+
+/// Whether `path` is the input file of `compile_ptx`, which is named after the cache key (64 hex digits).
+fn is_compiled_kernel(path: &str) -> bool {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or_default();
+    name.strip_suffix(".cu")
+        .is_some_and(|key| key.len() == 64 && key.bytes().all(|byte| byte.is_ascii_hexdigit()))
+}
+
+/// Parses the output of `ptxas -v`, e.g.
+///
+/// ```text
+/// ptxas info    : Compiling entry function 'add' for 'sm_80'
+/// ptxas info    : Used 8 registers, 1024 bytes smem, 356 bytes cmem[0]
+/// ```
+pub fn parse_ptxas_info(output: &str) -> HashMap<String, Resources> {
+    let mut resources = HashMap::new();
+    let mut current = None;
+
+    for line in output.lines() {
+        let Some((_, info)) = line.split_once(':') else {
+            continue;
+        };
+        let info = info.trim();
+
+        if let Some(function) = info.strip_prefix("Compiling entry function") {
+            let name = function.trim().split('\'').nth(1).unwrap_or_default();
+            current = Some(name.to_string());
+            resources.insert(name.to_string(), Resources::default());
+            continue;
+        }
+
+        let (Some(name), Some(usage)) = (&current, info.strip_prefix("Used")) else {
+            continue;
+        };
+        let entry = resources.entry(name.clone()).or_default();
+
+        for part in usage.split(',') {
+            let mut words = part.split_whitespace();
+            let Some(Ok(amount)) = words.next().map(str::parse) else {
+                continue;
+            };
+            match (words.next(), words.next()) {
+                (Some("registers"), _) => entry.registers = amount,
+                (Some("bytes"), Some("smem")) => entry.shared_mem_bytes = amount,
+                _ => {}
+            }
+        }
+    }
+
+    resources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_entries, parse_ptxas_info, parse_source_map, Entry, Resources};
+
+    const KEY: &str = "3f2a3f2a3f2a3f2a3f2a3f2a3f2a3f2a3f2a3f2a3f2a3f2a3f2a3f2a3f2a3f2a";
+
+    #[test]
+    fn entries_and_param_types() {
+        let ptx = concat!(
+            ".visible .entry add(\n",
+            "\t.param .u64 add_param_0,\n",
+            "\t.param .u32 add_param_1\n",
+            ")\n",
+            "{\n}\n",
+            ".visible .entry with_struct(\n",
+            "\t.param .align 8 .b8 with_struct_param_0[16],\n",
+            "\t.param .u64 .ptr .global .align 4 with_struct_param_1\n",
+            ")\n",
+            ".visible .entry no_params()\n",
+        );
+
+        assert_eq!(
+            parse_entries(ptx),
+            [
+                Entry {
+                    name: "add".to_string(),
+                    params: vec!["u64".to_string(), "u32".to_string()],
+                },
+                Entry {
+                    name: "with_struct".to_string(),
+                    params: vec!["b8[16]".to_string(), "u64".to_string()],
+                },
+                Entry {
+                    name: "no_params".to_string(),
+                    params: Vec::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn source_map_of_kernel_and_header() {
+        let ptx = format!(
+            concat!(
+                ".file\t1 \"{key}.cu\"\n",
+                ".file\t2 \"/usr/local/cuda/include\", \"common.h\"\n",
+                ".visible .entry add(\n",
+                ")\n",
+                "{{\n",
+                "\t.loc\t1 4 9\n",
+                "\tld.global.f32 %f1, [%rd1];\n",
+                "\t.loc\t1 4 5\n",
+                "\t.loc\t2 7 1, function_name $L__info_string0, inlined_at 1 4 9\n",
+                "\tadd.f32 %f2, %f1, %f1;\n",
+                "\t.loc\t1 0 0\n",
+                "\tret;\n",
+                "}}\n",
+            ),
+            key = KEY
+        );
+
+        let source_map = parse_source_map(&ptx);
+        let lines = source_map
+            .iter()
+            .map(|line| {
+                (
+                    line.ptx_line,
+                    line.function.as_str(),
+                    line.file.as_deref(),
+                    line.line,
+                )
+            })
+            .collect::<Vec<_>>();
+        // the second `.loc 1 4` is merged, line 0 is skipped
+        assert_eq!(
+            lines,
+            [
+                (6, "add", None, 4),
+                (9, "add", Some("/usr/local/cuda/include/common.h"), 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn ptxas_resource_usage() {
+        let output = concat!(
+            "ptxas info    : 0 bytes gmem\n",
+            "ptxas info    : Compiling entry function 'add' for 'sm_80'\n",
+            "ptxas info    : Function properties for add\n",
+            "    0 bytes stack frame, 0 bytes spill stores, 0 bytes spill loads\n",
+            "ptxas info    : Used 8 registers, 1024 bytes smem, 356 bytes cmem[0]\n",
+            "ptxas info    : Compiling entry function 'copy' for 'sm_80'\n",
+            "ptxas info    : Used 4 registers, 352 bytes cmem[0]\n",
+        );
+
+        let resources = parse_ptxas_info(output);
+        assert_eq!(
+            resources["add"],
+            Resources {
+                registers: 8,
+                shared_mem_bytes: 1024,
+            }
+        );
+        assert_eq!(
+            resources["copy"],
+            Resources {
+                registers: 4,
+                shared_mem_bytes: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{
+    build_script::BuildError,
+    compiler::CudaCompiler,
+    cuda::{compile_ptx, has_errors, CompileError},
+    includes::resolve_includes,
+    opencl::{run_clang, validate_opencl, Clang, ClangOutput},
+    options::CudaOptions,
+};
+
+/// How a changed kernel file is turned into the value of a [`HotReload`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reload {
+    /// The source is used as is, e.g. for NVRTC.
+    Source,
+    /// The CUDA source is compiled to PTX with the options of the macro, like `cuda!` does at build time.
+    /// The compiler is selected with `CUSTOS_CUDA_COMPILER` at runtime.
+    Ptx(CudaOptions),
+    /// The OpenCL C source is checked like `opencl!` does at build time.
+    OpenCl,
+}
+
+/// A kernel that is reloaded from its file when the file changes, emitted by `cuda!(hot_reload = true, ...)`
+/// and `opencl!(hot_reload = true, ...)`.
+///
+/// In debug builds, it holds the path of the kernel file and the value embedded at build time.
+/// Every [`get`](HotReload::get) compares the modification time of the file and recompiles or rereads it if it changed.
+/// If the new version does not compile, the previous value is kept and [`last_error`](HotReload::last_error)
+/// returns the error until a later version compiles.
+/// In release builds, it only holds the embedded value and never touches the file system.
+#[derive(Debug)]
+pub struct HotReload {
+    path: Option<PathBuf>,
+    reload: Reload,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    modified: Option<SystemTime>,
+    current: String,
+    error: Option<BuildError>,
+}
+
+impl HotReload {
+    /// A kernel that is reloaded from `path`. `embedded` is used until the file changes, or if it cannot be read.
+    pub fn new(path: impl Into<PathBuf>, embedded: impl Into<String>, reload: Reload) -> HotReload {
+        let path = path.into();
+        HotReload {
+            state: Mutex::new(State {
+                modified: modified(&path),
+                current: embedded.into(),
+                error: None,
+            }),
+            path: Some(path),
+            reload,
+        }
+    }
+
+    /// A kernel that always is `embedded`.
+    pub fn embedded(embedded: impl Into<String>) -> HotReload {
+        HotReload {
+            path: None,
+            reload: Reload::Source,
+            state: Mutex::new(State {
+                modified: None,
+                current: embedded.into(),
+                error: None,
+            }),
+        }
+    }
+
+    /// The kernel file, if the kernel is reloaded.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Reloads the kernel if its file changed, then returns the current PTX or source.
+    /// If the changed file does not compile, this is the previous version, see [`last_error`](HotReload::last_error).
+    pub fn get(&self) -> String {
+        // the error is kept for `last_error`
+        let _ = self.reload();
+        self.current()
+    }
+
+    /// The current PTX or source, without checking the file.
+    pub fn current(&self) -> String {
+        self.state().current.clone()
+    }
+
+    /// The error of the last version of the file, if it did not compile.
+    /// It is cleared when a later version compiles.
+    pub fn last_error(&self) -> Option<BuildError> {
+        self.state().error.clone()
+    }
+
+    /// Reloads the kernel if its file changed since the last check.
+    /// Returns the new PTX or source, `None` if the file did not change, or the error if the new version does not compile.
+    /// A version is only compiled once, so an error is returned once as well.
+    pub fn reload(&self) -> Result<Option<String>, BuildError> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(None);
+        };
+        let modified = modified(path);
+        {
+            let mut state = self.state();
+            if modified.is_none() || state.modified == modified {
+                return Ok(None);
+            }
+            state.modified = modified;
+        }
+
+        let loaded = self.load(path);
+        let mut state = self.state();
+        match loaded {
+            Ok(value) => {
+                state.current.clone_from(&value);
+                state.error = None;
+                Ok(Some(value))
+            }
+            Err(err) => {
+                state.error = Some(err.clone());
+                Err(err)
+            }
+        }
+    }
+
+    fn load(&self, path: &Path) -> Result<String, BuildError> {
+        let src = std::fs::read_to_string(path)
+            .map_err(|err| BuildError::Io(format!("Could not read {}: {err}", path.display())))?;
+        let diagnostics_err = |diagnostics| BuildError::Diagnostics {
+            file: path.to_path_buf(),
+            diagnostics,
+        };
+
+        match &self.reload {
+            Reload::Source => Ok(src),
+            Reload::Ptx(options) => {
+                let mut options = options.clone();
+                options
+                    .include_dirs
+                    .extend(path.parent().map(Path::to_path_buf));
+                let includes = resolve_includes(&src, path.parent(), &options.include_dirs);
+                match compile_ptx(&src, &includes, &options, &CudaCompiler::from_env()) {
+                    Ok(compiled) => Ok(compiled.ptx),
+                    Err(CompileError::Io(message)) => Err(BuildError::Io(message)),
+                    Err(CompileError::Diagnostics(diagnostics)) => {
+                        Err(diagnostics_err(diagnostics))
+                    }
+                }
+            }
+            Reload::OpenCl => {
+                let mut diagnostics = validate_opencl(&src);
+                if let Some(clang) = Clang::from_env().filter(|_| !has_errors(&diagnostics)) {
+                    let (_, checked) =
+                        run_clang(&src, path.parent(), &clang, ClangOutput::SyntaxOnly)
+                            .map_err(BuildError::Io)?;
+                    diagnostics.extend(checked);
+                }
+                match has_errors(&diagnostics) {
+                    true => Err(diagnostics_err(diagnostics)),
+                    false => Ok(src),
+                }
+            }
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    };
+
+    use super::{HotReload, Reload};
+    use crate::build_script::BuildError;
+
+    fn kernel_file(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("custos-macro-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Rewrites the file with a later modification time, the resolution of the file system may be coarse.
+    fn change(path: &Path, contents: &str, seconds: u64) {
+        std::fs::write(path, contents).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(seconds))
+            .unwrap();
+    }
+
+    #[test]
+    fn reloads_when_the_modification_time_changes() {
+        let path = kernel_file("source.cu", "// v1");
+        let kernel = HotReload::new(&path, "embedded", Reload::Source);
+
+        assert_eq!(kernel.path(), Some(path.as_path()));
+        assert_eq!(kernel.get(), "embedded");
+        assert_eq!(kernel.reload(), Ok(None));
+
+        change(&path, "// v2", 10);
+        assert_eq!(kernel.reload(), Ok(Some("// v2".to_string())));
+        assert_eq!(kernel.reload(), Ok(None));
+
+        change(&path, "// v3", 20);
+        assert_eq!(kernel.get(), "// v3");
+        assert_eq!(kernel.current(), "// v3");
+    }
+
+    #[test]
+    fn keeps_the_previous_version_and_returns_the_error() {
+        let path = kernel_file("broken.cl", "__kernel void add() {}");
+        let kernel = HotReload::new(&path, "__kernel void add() {}", Reload::OpenCl);
+
+        change(&path, "__kernel void add() {\n", 10);
+        let Err(BuildError::Diagnostics { file, diagnostics }) = kernel.reload() else {
+            panic!("the broken kernel was loaded");
+        };
+        assert_eq!(file, path);
+        assert_eq!(diagnostics[0].message, "unclosed delimiter `{`");
+        assert_eq!(kernel.current(), "__kernel void add() {}");
+
+        // the error is returned once, but stays available
+        assert_eq!(kernel.get(), "__kernel void add() {}");
+        assert!(kernel.last_error().is_some());
+
+        change(&path, "__kernel void sub() {}", 20);
+        assert_eq!(kernel.get(), "__kernel void sub() {}");
+        assert_eq!(kernel.last_error(), None);
+    }
+
+    #[test]
+    fn embedded_kernels_never_reload() {
+        let kernel = HotReload::embedded("embedded");
+        assert_eq!(kernel.path(), None);
+        assert_eq!(kernel.reload(), Ok(None));
+        assert_eq!(kernel.get(), "embedded");
+    }
+}
@@ -0,0 +1,193 @@
+use std::{error::Error, ops::Range};
+
+use naga::{
+    back::spv::{self, WriterFlags},
+    valid::{Capabilities, ModuleInfo, ValidationFlags, Validator},
+    Module,
+};
+
+use crate::diagnostics::Diagnostic;
+
+/// Parses WGSL, a parse error becomes a diagnostic at its location.
+pub fn parse_wgsl(src: &str) -> Result<Module, Vec<Diagnostic>> {
+    naga::front::wgsl::parse_str(src).map_err(|err| {
+        let labels = err
+            .labels()
+            .map(|(span, label)| (span.to_range(), label))
+            .collect::<Vec<_>>();
+        vec![diagnostic(src, err.message(), &labels)]
+    })
+}
+
+/// Parses a GLSL compute shader, every parse error becomes a diagnostic at its location.
+#[cfg(feature = "glsl")]
+pub fn parse_glsl(src: &str) -> Result<Module, Vec<Diagnostic>> {
+    naga::front::glsl::Frontend::default()
+        .parse(
+            &naga::front::glsl::Options::from(naga::ShaderStage::Compute),
+            src,
+        )
+        .map_err(|errors| {
+            errors
+                .errors
+                .iter()
+                .map(|err| diagnostic(src, &err.kind.to_string(), &[(err.meta.to_range(), "")]))
+                .collect()
+        })
+}
+
+/// Writes SPIR-V 1.0 without debug info, so the output only depends on the shader.
+pub fn write_spirv(shader: &Module, info: &ModuleInfo) -> Result<Vec<u32>, Vec<Diagnostic>> {
+    let options = spv::Options {
+        flags: WriterFlags::ADJUST_COORDINATE_SPACE
+            | WriterFlags::LABEL_VARYINGS
+            | WriterFlags::CLAMP_FRAG_DEPTH,
+        ..Default::default()
+    };
+    spv::write_vec(shader, info, &options, None).map_err(|err| {
+        vec![Diagnostic::error(format!(
+            "could not write SPIR-V: {}",
+            error_chain(&err)
+        ))]
+    })
+}
+
+/// Validates a naga module, e.g. types, uniformity and the use of bindings.
+///
+/// `capabilities` are the optional features the shader may use, e.g. `f64` or push constants.
+/// [`Capabilities::default()`] is what every wgpu adapter supports.
+pub fn validate(
+    module: &Module,
+    src: &str,
+    capabilities: Capabilities,
+) -> Result<ModuleInfo, Vec<Diagnostic>> {
+    Validator::new(ValidationFlags::all(), capabilities)
+        .validate(module)
+        .map_err(|err| {
+            let labels = err
+                .spans()
+                .map(|(span, label)| (span.to_range(), label.as_str()))
+                .collect::<Vec<_>>();
+            vec![diagnostic(src, &error_chain(err.as_inner()), &labels)]
+        })
+}
+
+/// A capability by its name in snake case, e.g. `float64` or `immediates` (push constants).
+pub fn capability(name: &str) -> Option<Capabilities> {
+    Capabilities::from_name(&name.to_uppercase())
+}
+
+/// Joins an error with its sources, naga nests the actual cause, e.g.
+/// `Function [0] 'main' is invalid: Expression [3] is invalid: ...`
+pub fn error_chain(err: &dyn Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
+}
+
+/// A diagnostic at the first labeled byte range of `src`, the labels become context lines.
+pub fn diagnostic(src: &str, message: &str, labels: &[(Option<Range<usize>>, &str)]) -> Diagnostic {
+    let location = labels
+        .iter()
+        .find_map(|(range, _)| range.clone())
+        .map(|range| line_column(src, range.start));
+
+    let context = labels
+        .iter()
+        .filter(|(_, label)| !label.is_empty())
+        .map(|(range, label)| match range {
+            Some(range) => {
+                let (line, column) = line_column(src, range.start);
+                format!("  {line}:{column}: {label}")
+            }
+            None => format!("  {label}"),
+        })
+        .collect();
+
+    Diagnostic {
+        line: location.map(|(line, _)| line),
+        column: location.map(|(_, column)| column),
+        context,
+        ..Diagnostic::error(message)
+    }
+}
+
+/// The 1-based line and char column of a byte offset.
+fn line_column(src: &str, offset: usize) -> (usize, usize) {
+    let before = src.get(..offset).unwrap_or(src);
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use naga::valid::Capabilities;
+
+    use super::{capability, parse_wgsl, validate, write_spirv};
+    use crate::diagnostics::Diagnostic;
+
+    fn check(src: &str, capabilities: Capabilities) -> Result<Vec<u32>, Vec<Diagnostic>> {
+        let module = parse_wgsl(src)?;
+        let info = validate(&module, src, capabilities)?;
+        write_spirv(&module, &info)
+    }
+
+    #[test]
+    fn parse_errors_at_their_location() {
+        let src = "@compute @workgroup_size(1)\nfn main() {\n    let x = ;\n}\n";
+        let diagnostics = check(src, Capabilities::default()).unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            (diagnostics[0].line, diagnostics[0].column),
+            (Some(3), Some(13))
+        );
+        assert!(
+            diagnostics[0].message.contains("expected"),
+            "{:?}",
+            diagnostics[0]
+        );
+    }
+
+    #[test]
+    fn validation_errors_at_their_location() {
+        let src = "@compute @workgroup_size(1)\nfn main() {\n    let x: u32 = 1.0;\n}\n";
+        let diagnostics = check(src, Capabilities::default()).unwrap_err();
+
+        assert_eq!(diagnostics[0].line, Some(3));
+        assert!(!diagnostics[0].context.is_empty(), "{:?}", diagnostics[0]);
+    }
+
+    #[test]
+    fn optional_features_need_their_capability() {
+        let f64_src = "@group(0) @binding(0) var<storage, read_write> out: array<f64>;\n\n@compute @workgroup_size(1)\nfn main() {\n    out[0] = 1.0lf;\n}\n";
+        let immediate_src = "var<immediate> scale: f32;\n@group(0) @binding(0) var<storage, read_write> out: array<f32>;\n\n@compute @workgroup_size(1)\nfn main() {\n    out[0] = scale;\n}\n";
+
+        assert!(check(f64_src, Capabilities::default()).is_err());
+        assert!(check(immediate_src, Capabilities::default()).is_err());
+
+        let spirv = check(f64_src, capability("float64").unwrap()).unwrap();
+        assert_eq!(spirv[0], 0x0723_0203);
+        assert!(check(immediate_src, capability("immediates").unwrap()).is_ok());
+        assert_eq!(capability("f64"), None);
+    }
+
+    #[cfg(feature = "glsl")]
+    #[test]
+    fn glsl_errors_at_their_location() {
+        let src =
+            "#version 450\nlayout(local_size_x = 1) in;\n\nvoid main() {\n    float x = y;\n}\n";
+        let diagnostics = super::parse_glsl(src).unwrap_err();
+
+        assert_eq!(diagnostics[0].line, Some(5));
+        assert!(diagnostics[0].message.contains('y'), "{:?}", diagnostics[0]);
+    }
+}
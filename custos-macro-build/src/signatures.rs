@@ -0,0 +1,348 @@
+use crate::c_lexer::{tokenize, Token, TokenKind};
+
+/// An `extern "C" __global__` or OpenCL `__kernel` declaration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelSignature {
+    pub name: String,
+    pub params: Vec<Param>,
+    /// 1-based line of the kernel name.
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Param {
+    pub name: String,
+    /// The type without qualifiers and pointers, e.g. `unsigned int` for `const unsigned int* __restrict__`.
+    pub ty: String,
+    pub pointers: usize,
+    /// An OpenCL `__local` pointer, its memory cannot be passed from the host.
+    pub local: bool,
+}
+
+/// Qualifiers that do not change how an argument is passed.
+const QUALIFIERS: &[&str] = &[
+    "const",
+    "volatile",
+    "restrict",
+    "__restrict",
+    "__restrict__",
+];
+
+/// OpenCL C address space and access qualifiers, in addition to [`QUALIFIERS`].
+const OPENCL_QUALIFIERS: &[&str] = &[
+    "__global",
+    "global",
+    "__constant",
+    "constant",
+    "__local",
+    "local",
+    "__private",
+    "private",
+    "__read_only",
+    "read_only",
+    "__write_only",
+    "write_only",
+    "__read_write",
+    "read_write",
+];
+
+/// Parses the signatures of all kernels with C linkage, e.g.
+///
+/// ```text
+/// extern "C" __global__ void add(const float* lhs, const float* rhs, float* out, int len)
+/// ```
+///
+/// Kernels inside an `extern "C" { ... }` block are found as well.
+/// Comments and preprocessor directives are skipped.
+pub fn parse_kernel_signatures(src: &str) -> Vec<KernelSignature> {
+    let tokens = significant(src);
+    let mut signatures = Vec::new();
+    // The brace depths of open `extern "C" { ... }` blocks.
+    let mut extern_blocks = Vec::new();
+    let mut depth = 0usize;
+    let mut statement_start = 0;
+
+    for (idx, token) in tokens.iter().enumerate() {
+        match token.text {
+            "{" => {
+                if idx >= 2 && is_extern_c(&tokens[idx - 2..idx]) {
+                    extern_blocks.push(depth);
+                }
+                depth += 1;
+                statement_start = idx + 1;
+            }
+            "}" => {
+                depth = depth.saturating_sub(1);
+                if extern_blocks.last() == Some(&depth) {
+                    extern_blocks.pop();
+                }
+                statement_start = idx + 1;
+            }
+            ";" => statement_start = idx + 1,
+            "__global__" => {
+                let has_c_linkage =
+                    !extern_blocks.is_empty() || is_extern_c(&tokens[statement_start..idx]);
+                if !has_c_linkage {
+                    continue;
+                }
+                if let Some(signature) = parse_declaration(&tokens[idx + 1..], &[]) {
+                    signatures.push(signature);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    signatures
+}
+
+/// Parses the signatures of all OpenCL C kernels, e.g.
+///
+/// ```text
+/// __kernel void add(__global const float* lhs, __global float* out, int len)
+/// ```
+///
+/// `kernel` without underscores and `__attribute__((...))` before or after `void` are accepted as well.
+pub fn parse_opencl_kernel_signatures(src: &str) -> Vec<KernelSignature> {
+    let tokens = significant(src);
+    let mut depth = 0usize;
+
+    tokens
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, token)| {
+            match token.text {
+                "{" => depth += 1,
+                "}" => depth = depth.saturating_sub(1),
+                "__kernel" | "kernel" if depth == 0 => {
+                    return parse_declaration(&tokens[idx + 1..], OPENCL_QUALIFIERS)
+                }
+                _ => {}
+            }
+            None
+        })
+        .collect()
+}
+
+/// Tokens without whitespace, comments and preprocessor directives.
+fn significant(src: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut at_line_start = true;
+    let mut in_directive = false;
+    // a directive ending with `\` continues on the next line
+    let mut continued = false;
+
+    for token in tokenize(src) {
+        match token.kind {
+            TokenKind::Whitespace => {
+                if token.text.contains('\n') {
+                    in_directive &= continued;
+                    at_line_start = true;
+                }
+                continued = false;
+                continue;
+            }
+            TokenKind::Comment => continue,
+            _ => {}
+        }
+
+        let is_directive = in_directive || (at_line_start && token.text == "#");
+        at_line_start = false;
+        if is_directive {
+            in_directive = true;
+            continued = token.text == "\\";
+            continue;
+        }
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+fn is_extern_c(tokens: &[Token]) -> bool {
+    tokens
+        .windows(2)
+        .any(|pair| pair[0].text == "extern" && pair[1].text == "\"C\"")
+}
+
+/// Parses `[__launch_bounds__(..)] void name(params)`, starting after `__global__` or `__kernel`.
+/// `qualifiers` are skipped in addition to [`QUALIFIERS`].
+fn parse_declaration(tokens: &[Token], qualifiers: &[&str]) -> Option<KernelSignature> {
+    let open = tokens.iter().position(|token| token.text == "(")?;
+    let mut head = &tokens[..open];
+    let mut rest = &tokens[open..];
+    let mut returns_void = head.iter().any(|token| token.text == "void");
+
+    // `__launch_bounds__(256)` or `__attribute__((...))` may appear before or after the return type
+    if head
+        .last()
+        .is_some_and(|token| matches!(token.text, "__launch_bounds__" | "__attribute__"))
+    {
+        let close = closing_paren(rest)?;
+        rest = &rest[close + 1..];
+        let open = rest.iter().position(|token| token.text == "(")?;
+        head = &rest[..open];
+        rest = &rest[open..];
+        returns_void |= head.iter().any(|token| token.text == "void");
+    }
+
+    let name = head.last().filter(|token| token.kind == TokenKind::Ident)?;
+    if !returns_void {
+        return None;
+    }
+
+    let close = closing_paren(rest)?;
+    let params = split_params(&rest[1..close])
+        .into_iter()
+        .map(|param| parse_param(param, qualifiers))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(KernelSignature {
+        name: name.text.to_string(),
+        params,
+        line: name.line,
+    })
+}
+
+/// The index of the `)` that closes the `(` at `tokens[0]`.
+fn closing_paren(tokens: &[Token]) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, token) in tokens.iter().enumerate() {
+        match token.text {
+            "(" => depth += 1,
+            ")" => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn split_params<'a, 'b>(tokens: &'b [Token<'a>]) -> Vec<&'b [Token<'a>]> {
+    if tokens.is_empty() || (tokens.len() == 1 && tokens[0].text == "void") {
+        return Vec::new();
+    }
+
+    let mut params = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    for (idx, token) in tokens.iter().enumerate() {
+        match token.text {
+            "(" | "[" | "<" => depth += 1,
+            ")" | "]" | ">" => depth -= 1,
+            "," if depth == 0 => {
+                params.push(&tokens[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&tokens[start..]);
+    params
+}
+
+/// Parses e.g. `const float* __restrict__ lhs`. The name of unnamed parameters is empty.
+fn parse_param(tokens: &[Token], qualifiers: &[&str]) -> Option<Param> {
+    let words = tokens
+        .iter()
+        .filter(|token| !QUALIFIERS.contains(&token.text) && !qualifiers.contains(&token.text))
+        .collect::<Vec<_>>();
+    let local = qualifiers.contains(&"__local")
+        && tokens
+            .iter()
+            .any(|token| matches!(token.text, "__local" | "local"));
+
+    let pointers = words.iter().filter(|token| token.text == "*").count();
+    let mut idents = words
+        .iter()
+        .filter(|token| token.kind == TokenKind::Ident)
+        .map(|token| token.text)
+        .collect::<Vec<_>>();
+
+    // references, arrays or templates cannot be passed from Rust
+    let is_plain = words
+        .iter()
+        .all(|token| token.kind == TokenKind::Ident || token.text == "*");
+
+    // only a type, e.g. `int` or `unsigned int`
+    let name = match idents.last() {
+        Some(last) if idents.len() > 1 && !is_type_word(last) => idents.pop()?.to_string(),
+        Some(_) => String::new(),
+        None => return None,
+    };
+
+    Some(Param {
+        name,
+        ty: match is_plain {
+            true => idents.join(" "),
+            false => tokens
+                .iter()
+                .map(|token| token.text)
+                .collect::<Vec<_>>()
+                .join(" "),
+        },
+        pointers: if is_plain { pointers } else { 0 },
+        local,
+    })
+}
+
+fn is_type_word(word: &str) -> bool {
+    matches!(
+        word,
+        "int"
+            | "unsigned"
+            | "signed"
+            | "char"
+            | "short"
+            | "long"
+            | "float"
+            | "double"
+            | "bool"
+            | "uint"
+            | "uchar"
+            | "ushort"
+            | "ulong"
+            | "half"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_opencl_kernel_signatures, Param};
+
+    #[test]
+    fn opencl_kernels_with_qualifiers_and_attributes() {
+        let src = concat!(
+            "// __kernel void in_comment()\n",
+            "__kernel void add(__global const float* x, __local float* tile, uint len) { x[0] += 1.0f; }\n",
+            "kernel __attribute__((reqd_work_group_size(64, 1, 1))) void mul(global float* x) {}\n",
+            "void helper(__global float* x) {}\n",
+        );
+
+        let kernels = parse_opencl_kernel_signatures(src);
+        assert_eq!(kernels.len(), 2);
+        assert_eq!((kernels[0].name.as_str(), kernels[0].line), ("add", 2));
+        assert_eq!(
+            kernels[0].params[1],
+            Param {
+                name: "tile".to_string(),
+                ty: "float".to_string(),
+                pointers: 1,
+                local: true,
+            }
+        );
+        assert_eq!(
+            (
+                kernels[0].params[2].ty.as_str(),
+                kernels[0].params[2].pointers
+            ),
+            ("uint", 0)
+        );
+        assert_eq!((kernels[1].name.as_str(), kernels[1].line), ("mul", 3));
+        assert!(!kernels[1].params[0].local);
+    }
+}
@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    cache::{cache_key, write_atomic},
+    options::{resolve_path, CudaOptions},
+};
+
+/// The first line of a vendored PTX file records which kernel it was generated for.
+const ORIGIN_PREFIX: &str = "// custos-macro vendored: ";
+
+/// Whether PTX is loaded from a checked-in directory instead of compiling it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Vendor {
+    /// Compiles the kernels.
+    Off,
+    /// Loads the PTX from the directory, the compiler is not invoked.
+    Verify(PathBuf),
+    /// Compiles the kernels and writes the PTX into the directory.
+    Regenerate(PathBuf),
+}
+
+impl Vendor {
+    /// Reads `CUSTOS_CUDA_VENDOR` (`verify` or `regenerate`) and `CUSTOS_CUDA_VENDOR_DIR`
+    /// (relative to the crate root, default: `ptx`).
+    pub fn from_env() -> Result<Vendor, String> {
+        let dir = || {
+            resolve_path(
+                std::env::var("CUSTOS_CUDA_VENDOR_DIR")
+                    .ok()
+                    .filter(|dir| !dir.is_empty())
+                    .unwrap_or_else(|| "ptx".to_string()),
+            )
+        };
+
+        match std::env::var("CUSTOS_CUDA_VENDOR").as_deref() {
+            Ok("verify") => Ok(Vendor::Verify(dir())),
+            Ok("regenerate") => Ok(Vendor::Regenerate(dir())),
+            Ok("" | "off") | Err(_) => Ok(Vendor::Off),
+            Ok(vendor) => Err(format!(
+                "Unknown CUSTOS_CUDA_VENDOR `{vendor}`, expected `verify`, `regenerate` or `off`."
+            )),
+        }
+    }
+}
+
+/// The name of the vendored PTX file of a kernel.
+///
+/// Unlike the cache key, it does not depend on the compiler, so builds without a CUDA toolkit find the file.
+/// It does not depend on the checkout either: include dirs are hashed relative to the crate root.
+pub fn vendored_file(
+    dir: &Path,
+    src: &str,
+    includes: &[PathBuf],
+    options: &CudaOptions,
+) -> PathBuf {
+    let headers = includes
+        .iter()
+        .map(|path| std::fs::read(path).unwrap_or_default())
+        .collect::<Vec<_>>();
+    let key = vendor_key(src, &headers, options, &resolve_path(""));
+    dir.join(format!("{key}.ptx"))
+}
+
+/// Hashes the source, the header contents and the options, with the include dirs relative to `root`.
+fn vendor_key(src: &str, headers: &[Vec<u8>], options: &CudaOptions, root: &Path) -> String {
+    let mut options = options.clone();
+    for dir in &mut options.include_dirs {
+        if let Ok(relative) = dir.strip_prefix(root) {
+            // the same key on every platform
+            let relative = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            *dir = PathBuf::from(relative);
+        }
+    }
+    let args = options.nvcc_args();
+
+    cache_key(
+        [src]
+            .into_iter()
+            .chain(args.iter().map(String::as_str))
+            .map(str::as_bytes)
+            .chain(headers.iter().map(Vec::as_slice)),
+    )
+}
+
+/// Describes a kernel for the first line of its vendored file, e.g. `src/lib.rs:10:5 (T = f32)`.
+/// Paths are relative to the crate root, the files are checked in.
+pub fn origin(location: Option<String>, instance: &str) -> String {
+    let location = location.unwrap_or_default();
+    let root = resolve_path("").display().to_string();
+    let location = location
+        .strip_prefix(&root)
+        .map_or(location.as_str(), |location| {
+            location.trim_start_matches(std::path::MAIN_SEPARATOR)
+        });
+    format!("{location} {instance}").trim().to_string()
+}
+
+/// Loads vendored PTX. `origin` describes the kernel, see [`origin`].
+///
+/// If the file is missing, the error names the file and, if there is one, the outdated file of the same kernel.
+pub fn load(file: &Path, origin: &str) -> Result<String, String> {
+    if let Ok(ptx) = std::fs::read_to_string(file) {
+        return Ok(match ptx.starts_with(ORIGIN_PREFIX) {
+            true => ptx.split_once('\n').map_or("", |(_, ptx)| ptx).to_string(),
+            false => ptx,
+        });
+    }
+
+    let dir = file.parent().unwrap_or(Path::new("."));
+    let outdated = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .find(|path| {
+            std::fs::read_to_string(path)
+                .is_ok_and(|ptx| ptx.lines().next() == Some(&format!("{ORIGIN_PREFIX}{origin}")))
+        });
+
+    let reason = match outdated {
+        Some(outdated) => format!(
+            "{} was generated from another version of this kernel, its source, headers or options changed",
+            outdated.display()
+        ),
+        None => "no PTX was generated for this source, headers and options".to_string(),
+    };
+    Err(format!(
+        "The vendored PTX of this kernel is out of date: {} is missing, {reason}. \
+         Regenerate it with `CUSTOS_CUDA_VENDOR=regenerate` and a CUDA compiler, then commit {}.",
+        file.display(),
+        dir.display()
+    ))
+}
+
+/// Writes the PTX of a kernel into the vendor directory.
+pub fn store(file: &Path, origin: &str, ptx: &str) -> Result<(), String> {
+    let io_err =
+        |err: std::io::Error| format!("Could not write the vendored PTX {}: {err}", file.display());
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir).map_err(io_err)?;
+    }
+
+    // kernels with the same source share a file, keep the first origin
+    let unchanged = std::fs::read_to_string(file).is_ok_and(|existing| {
+        existing
+            .split_once('\n')
+            .is_some_and(|(_, existing)| existing == ptx)
+    });
+    if unchanged {
+        return Ok(());
+    }
+
+    let contents = format!("{ORIGIN_PREFIX}{origin}\n{ptx}");
+    write_atomic(file, contents.as_bytes()).map_err(io_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{load, store, vendor_key};
+    use crate::options::CudaOptions;
+
+    fn options(root: &Path) -> CudaOptions {
+        CudaOptions {
+            arch: Some("sm_80".to_string()),
+            defines: vec![("N".to_string(), Some("4".to_string()))],
+            include_dirs: vec![root.join("kernels"), root.join("include/common")],
+            ..CudaOptions::default()
+        }
+    }
+
+    #[test]
+    fn key_does_not_depend_on_the_checkout() {
+        let headers = [b"#define ONE 1".to_vec()];
+        let key = |root: &str| {
+            let root = PathBuf::from(root);
+            vendor_key("__global__ void f() {}", &headers, &options(&root), &root)
+        };
+
+        assert_eq!(key("/home/a/custos"), key("/builds/ci/custos"));
+        assert_ne!(
+            key("/home/a/custos"),
+            vendor_key(
+                "__global__ void f() {}",
+                &headers,
+                &CudaOptions::default(),
+                Path::new("/home/a/custos")
+            )
+        );
+    }
+
+    #[test]
+    fn key_covers_the_source_headers_and_options() {
+        let root = Path::new("/crate");
+        let key = vendor_key("a", &[b"h".to_vec()], &options(root), root);
+
+        assert_ne!(key, vendor_key("b", &[b"h".to_vec()], &options(root), root));
+        assert_ne!(key, vendor_key("a", &[b"g".to_vec()], &options(root), root));
+        let mut other = options(root);
+        other.flags.push("--use_fast_math".to_string());
+        assert_ne!(key, vendor_key("a", &[b"h".to_vec()], &other, root));
+    }
+
+    #[test]
+    fn stores_and_loads_with_the_origin() {
+        let dir = std::env::temp_dir().join(format!("custos-vendor-test-{}", std::process::id()));
+        let file = dir.join("key.ptx");
+
+        store(&file, "src/lib.rs:3:5", ".entry f()").unwrap();
+        assert_eq!(load(&file, "src/lib.rs:3:5").unwrap(), ".entry f()");
+
+        let err = load(&dir.join("new.ptx"), "src/lib.rs:3:5").unwrap_err();
+        assert!(err.contains("was generated from another version of this kernel"));
+        let err = load(&dir.join("new.ptx"), "src/lib.rs:9:5").unwrap_err();
+        assert!(err.contains("no PTX was generated"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
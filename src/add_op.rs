@@ -1,7 +1,7 @@
 use proc_macro2::TokenStream;
 use syn::ExprCall;
 
-/// ```rust
+/// ```text
 /// apply_fn(x: &Buffer, out: &mut Buffer, f: fn());
 ///
 /// apply_fn(x, &mut out, f);
@@ -1 +1,438 @@
+use custos_macro_build::{
+    compiler::CudaCompiler,
+    cuda::{compile_ptx, CompileError},
+    ptx::{parse_source_map, SourceLine},
+    vendor::{self, vendored_file, Vendor},
+};
+use std::path::Path;
 
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned};
+
+use crate::{
+    cuda_module::cuda_module_expansion,
+    dtype::{instances, kernels_by_type},
+    hip::hip_kernels,
+    hot_reload::{hot_reload_handle, reload_ptx},
+    kernel_src::KernelSrc,
+    options::{track_env_vars, CudaInput, CudaMode, CudaOptions, Target, CUDA_ENV_VARS},
+};
+
+/// Expands `cuda!`, or `cuda_file!` if `literal_is_path` is set.
+pub fn cuda_expansion(input: TokenStream, literal_is_path: bool) -> TokenStream {
+    let input = match CudaInput::parse(input, literal_is_path) {
+        Ok(input) => input,
+        Err(err) => return err.to_compile_error(),
+    };
+    if input.hot_reload {
+        return hot_reload_kernel(&input);
+    }
+    if input.target == Target::Hip {
+        return hip_kernels(&input);
+    }
+    if input.source_map && input.module.is_none() {
+        return quote_spanned!(input.kernel.span()=> compile_error!(
+            "`source_map` needs `module`, the source map is generated as `<module>::SOURCE_MAP`."
+        ));
+    }
+
+    let mut track_env = track_env_vars(CUDA_ENV_VARS);
+    track_env.extend(input.kernel.track_files());
+    let mode = match CudaMode::from_env() {
+        Ok(mode) => mode,
+        Err(message) => {
+            let message = format!("custos-macro: {message}");
+            return quote_spanned!(input.kernel.span()=> compile_error!(#message));
+        }
+    };
+    let compiler = CudaCompiler::from_env();
+
+    if let Some(module) = &input.module {
+        let module = cuda_module_expansion(&input, module, mode, &compiler);
+        return quote! {
+            #track_env
+            #module
+        };
+    }
+
+    let CudaInput {
+        options,
+        kernel,
+        types,
+        ..
+    } = &input;
+
+    if types.is_empty() {
+        let (kernel, _) = embed_kernel(
+            kernel,
+            &kernel.src,
+            options,
+            &compiler,
+            mode,
+            compiler.name(),
+        );
+        return quote!({
+            #track_env
+            #kernel
+        });
+    }
+
+    let kernels = instances(types, &kernel.src, options, compiler.name()).map(|instance| {
+        let (embedded, _) = embed_kernel(
+            kernel,
+            &instance.src,
+            &instance.options,
+            &compiler,
+            mode,
+            &instance.name,
+        );
+        (instance.ty, embedded)
+    });
+
+    let output = match mode {
+        CudaMode::Ptx => quote!(custos::cuda::Ptx),
+        CudaMode::Runtime => quote!(custos::cuda::KernelSrc),
+    };
+    let kernels = kernels_by_type(output, kernels);
+
+    quote!({
+        #track_env
+        #kernels
+    })
+}
+
+/// Expands `cuda!(hot_reload = true, ...)` to a `custos_macro_build::HotReload` of the PTX or, in runtime mode, the source.
+fn hot_reload_kernel(input: &CudaInput) -> TokenStream {
+    let CudaInput {
+        options,
+        kernel,
+        types,
+        module,
+        source_map,
+        target,
+        ..
+    } = input;
+    let unsupported = match () {
+        _ if !types.is_empty() => Some("`types`"),
+        _ if module.is_some() => Some("`module`"),
+        _ if *source_map => Some("`source_map`"),
+        _ if *target == Target::Hip => Some("`target = \"hip\"`"),
+        _ => None,
+    };
+    if let Some(option) = unsupported {
+        let message = format!("`hot_reload` cannot be combined with {option}");
+        return quote_spanned!(kernel.span()=> compile_error!(#message));
+    }
+
+    let mut track_env = track_env_vars(CUDA_ENV_VARS);
+    track_env.extend(kernel.track_files());
+    let mode = match CudaMode::from_env() {
+        Ok(mode) => mode,
+        Err(message) => {
+            let message = format!("custos-macro: {message}");
+            return quote_spanned!(kernel.span()=> compile_error!(#message));
+        }
+    };
+
+    let (diagnostics, embedded, reload) = match mode {
+        CudaMode::Ptx => {
+            let compiler = CudaCompiler::from_env();
+            let (diagnostics, ptx) =
+                compile_kernel(kernel, &kernel.src, options, &compiler, compiler.name());
+            (diagnostics, ptx.unwrap_or_default(), reload_ptx(options))
+        }
+        CudaMode::Runtime => (
+            TokenStream::new(),
+            kernel.src.clone(),
+            quote!(::custos_macro_build::Reload::Source),
+        ),
+    };
+    let handle = hot_reload_handle(kernel, &embedded, reload);
+
+    quote!({
+        #track_env
+        #diagnostics
+        #handle
+    })
+}
+
+/// Expands to the embedded kernel, i.e. a `custos::cuda::Ptx` or, in runtime mode, a `custos::cuda::KernelSrc`.
+/// Compiler diagnostics are reported at their location in `kernel`, prefixed with `name`.
+///
+/// Also returns the PTX, if the kernel was compiled successfully.
+pub fn embed_kernel(
+    kernel: &KernelSrc,
+    src: &str,
+    options: &CudaOptions,
+    compiler: &CudaCompiler,
+    mode: CudaMode,
+    name: &str,
+) -> (TokenStream, Option<String>) {
+    if mode == CudaMode::Runtime {
+        let options = options.nvrtc_args();
+        let kernel = quote! {
+            custos::cuda::KernelSrc {
+                mode: custos::cuda::KernelMode::Runtime,
+                src: #src.to_string(),
+                options: vec![#(#options.to_string()),*],
+            }
+        };
+        return (kernel, None);
+    }
+
+    let (diagnostics, ptx) = compile_kernel(kernel, src, options, compiler, name);
+
+    let ptx_src = ptx.as_deref().unwrap_or_default();
+    let kernel = quote!({
+        #diagnostics
+        custos::cuda::Ptx {
+            src: #ptx_src.to_string()
+        }
+    });
+    (kernel, ptx)
+}
+
+/// Maps the `.loc` directives of the PTX to `SourceLine`s of the `module`, see [`cuda_module_expansion`],
+/// i.e. the kernel lines to the Rust file and line of the literal or to the line of the kernel file or header.
+pub fn source_map(kernel: &KernelSrc, ptx: &str) -> Vec<TokenStream> {
+    parse_source_map(ptx)
+        .into_iter()
+        .map(|source_line| {
+            let SourceLine {
+                ptx_line,
+                function,
+                file,
+                line,
+                column,
+            } = source_line;
+            let (file, line) = match file {
+                Some(header) => (header, line),
+                None => kernel
+                    .position(line, column)
+                    .map_or((String::new(), line), |(file, line, _)| (file, line)),
+            };
+            // kernel files are relative to the crate root, like Rust files
+            let file = std::env::var("CARGO_MANIFEST_DIR")
+                .ok()
+                .and_then(|root| {
+                    Some(
+                        Path::new(&file)
+                            .strip_prefix(root)
+                            .ok()?
+                            .display()
+                            .to_string(),
+                    )
+                })
+                .unwrap_or(file);
+            let ptx_line = ptx_line as u32;
+            let line = line as u32;
+
+            quote! {
+                SourceLine {
+                    ptx_line: #ptx_line,
+                    kernel: #function,
+                    file: #file,
+                    line: #line,
+                }
+            }
+        })
+        .collect()
+}
+
+/// Compiles `src` to PTX and converts the compiler diagnostics into tokens, see [`KernelSrc::emit_diagnostics`].
+///
+/// With `CUSTOS_CUDA_VENDOR=verify`, the PTX is loaded from the vendor directory instead,
+/// with `CUSTOS_CUDA_VENDOR=regenerate`, the compiled PTX is written into it, see [`Vendor`].
+pub fn compile_kernel(
+    kernel: &KernelSrc,
+    src: &str,
+    options: &CudaOptions,
+    compiler: &CudaCompiler,
+    name: &str,
+) -> (TokenStream, Option<String>) {
+    let error = |message: String| {
+        let message = format!("custos-macro: {message}");
+        (
+            quote_spanned!(kernel.span()=> compile_error!(#message);),
+            None,
+        )
+    };
+
+    let vendor = match Vendor::from_env() {
+        Ok(vendor) => vendor,
+        Err(message) => return error(message),
+    };
+    // the compiler does not matter, e.g. `(T = f32)` of `nvcc (T = f32)`
+    let instance = name.strip_prefix(compiler.name()).unwrap_or(name);
+    let origin = vendor::origin(kernel.location(1, 1), instance);
+
+    let dir = match &vendor {
+        Vendor::Off => None,
+        Vendor::Verify(dir) | Vendor::Regenerate(dir) => Some(dir),
+    };
+    let vendored = dir.map(|dir| vendored_file(dir, src, &kernel.includes, options));
+
+    if let (Vendor::Verify(_), Some(file)) = (&vendor, &vendored) {
+        return match vendor::load(file, &origin) {
+            Ok(ptx) => {
+                let file = file.display().to_string();
+                (
+                    quote!(
+                        const _: &[u8] = include_bytes!(#file);
+                    ),
+                    Some(ptx),
+                )
+            }
+            Err(message) => error(message),
+        };
+    }
+
+    match compile_ptx(src, &kernel.includes, options, compiler) {
+        Ok(compiled) => {
+            if let Some(file) = &vendored {
+                if let Err(message) = vendor::store(file, &origin, &compiled.ptx) {
+                    return error(message);
+                }
+            }
+            (
+                kernel.emit_diagnostics(name, &compiled.diagnostics),
+                Some(compiled.ptx),
+            )
+        }
+        Err(CompileError::Io(message)) => error(message),
+        Err(CompileError::Diagnostics(diagnostics)) => {
+            (kernel.emit_diagnostics(name, &diagnostics), None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proc_macro2::Span;
+    use syn::LitStr;
+
+    use custos_macro_build::compiler::CudaCompiler;
+
+    use super::embed_kernel;
+    use crate::{
+        kernel_src::KernelSrc,
+        options::{CudaMode, CudaOptions},
+    };
+
+    fn kernel(src: &str) -> KernelSrc {
+        // a unique source, so the stub is not skipped by a cache hit of a previous run
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let src = format!("// {} {nanos}\n{src}", std::process::id());
+        KernelSrc::from_lit(LitStr::new(&src, Span::call_site()))
+    }
+
+    /// A compiler that rejects `undefined_symbol` in nvcc format and otherwise writes fixed PTX.
+    #[cfg(unix)]
+    fn stub() -> CudaCompiler {
+        use std::{os::unix::fs::PermissionsExt, sync::OnceLock};
+
+        static STUB: OnceLock<String> = OnceLock::new();
+        let path = STUB.get_or_init(|| {
+            let script = r#"#!/bin/sh
+[ "$1" = "--version" ] && { echo "stub 1.0"; exit 0; }
+for arg; do
+    [ "$prev" = "-o" ] && out="$arg"
+    case "$arg" in *.cu) input="$arg";; esac
+    prev="$arg"
+done
+line=$(grep -n undefined_symbol "$input" | head -n 1 | cut -d: -f1)
+if [ -n "$line" ]; then
+    echo "$input($line): error: identifier \"undefined_symbol\" is undefined" >&2
+    exit 2
+fi
+echo ".visible .entry stub_entry()" > "$out"
+"#;
+            let path =
+                std::env::temp_dir().join(format!("custos-macro-stub-{}", std::process::id()));
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path.display().to_string()
+        });
+        CudaCompiler::Custom(path.clone())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn embeds_the_ptx_of_a_stub() {
+        let kernel = kernel("__global__ void add(float* x) { x[0] = 1.0f; }");
+        let (tokens, ptx) = embed_kernel(
+            &kernel,
+            &kernel.src,
+            &CudaOptions::default(),
+            &stub(),
+            CudaMode::Ptx,
+            "stub",
+        );
+
+        assert_eq!(ptx.as_deref(), Some(".visible .entry stub_entry()\n"));
+        let tokens = tokens.to_string();
+        assert!(
+            tokens.contains("custos :: cuda :: Ptx { src : \".visible .entry stub_entry()\\n\" . to_string () }"),
+            "{tokens}"
+        );
+        assert!(!tokens.contains("compile_error"), "{tokens}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_the_errors_of_a_stub_at_the_kernel() {
+        let kernel = kernel("__global__ void add(float* x) {\n    x[0] = undefined_symbol;\n}");
+        let (tokens, ptx) = embed_kernel(
+            &kernel,
+            &kernel.src,
+            &CudaOptions::default(),
+            &stub(),
+            CudaMode::Ptx,
+            "stub",
+        );
+
+        assert_eq!(ptx, None);
+        let tokens = tokens.to_string();
+        assert!(tokens.contains("compile_error"), "{tokens}");
+        assert!(
+            tokens.contains("stub error: identifier \\\"undefined_symbol\\\" is undefined"),
+            "{tokens}"
+        );
+        // line 1 is the comment of `kernel`
+        assert!(tokens.contains("kernel line 3"), "{tokens}");
+    }
+
+    #[test]
+    fn runtime_mode_embeds_the_source_and_options() {
+        let kernel = kernel("__global__ void add(float* x) { x[0] = BLOCK; }");
+        let options = CudaOptions {
+            arch: Some("sm_80".to_string()),
+            defines: vec![("BLOCK".to_string(), Some("256".to_string()))],
+            ..CudaOptions::default()
+        };
+        let (tokens, ptx) = embed_kernel(
+            &kernel,
+            &kernel.src,
+            &options,
+            &CudaCompiler::Custom("never-invoked".to_string()),
+            CudaMode::Runtime,
+            "stub",
+        );
+
+        assert_eq!(ptx, None);
+        let tokens = tokens.to_string();
+        assert!(
+            tokens.contains("custos :: cuda :: KernelMode :: Runtime"),
+            "{tokens}"
+        );
+        assert!(
+            tokens.contains(
+                "options : vec ! [\"--gpu-architecture=sm_80\" . to_string () , \"-DBLOCK=256\" . to_string ()]"
+            ),
+            "{tokens}"
+        );
+    }
+}
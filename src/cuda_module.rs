@@ -0,0 +1,253 @@
+use custos_macro_build::{
+    compiler::CudaCompiler,
+    cuda::resource_usage,
+    ptx::{parse_entries, Entry, Resources},
+};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote, quote_spanned};
+use syn::Ident;
+
+use crate::{
+    cuda::{embed_kernel, source_map},
+    dtype::{instances, type_name},
+    launch::{launch_config_struct, launch_fns},
+    options::{CudaInput, CudaMode, CudaOptions},
+};
+
+/// Expands `cuda!(module = name, ...)` to a module containing the PTX and the metadata of every kernel.
+///
+/// ```ignore
+/// pub mod name {
+///     pub struct Kernel { pub name: &'static str, pub params: &'static [&'static str], ... }
+///
+///     pub fn ptx() -> custos::cuda::Ptx { ... }
+///     pub const KERNELS: &[Kernel] = &[ADD];
+///     pub const ADD: Kernel = Kernel { name: "add", params: &["u64", "u64", "u32"], ... };
+/// }
+/// ```
+///
+/// Every `extern "C" __global__` kernel also gets a typed launch function, e.g. `name::add(&device, config, &lhs, ...)`.
+///
+/// With `source_map = true`, there is a `SourceLine` struct and a `SOURCE_MAP` const next to `KERNELS`.
+///
+/// With `types`, there is a submodule with these items per datatype, e.g. `name::f32::ADD`,
+/// and `name::ptx::<T>()` returns the PTX of a datatype.
+pub fn cuda_module_expansion(
+    input: &CudaInput,
+    module: &Ident,
+    mode: CudaMode,
+    compiler: &CudaCompiler,
+) -> TokenStream {
+    if mode == CudaMode::Runtime {
+        return quote_spanned! {module.span()=>
+            compile_error!("`module` is not available in the runtime mode, the kernel metadata is read from the compiled PTX.");
+        };
+    }
+
+    let launch_config = launch_config_struct();
+    let source_line_struct = match input.source_map {
+        true => quote! {
+            /// The source line that the PTX from `ptx_line` on was generated from.
+            #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+            pub struct SourceLine {
+                /// The line of the PTX (1-based).
+                pub ptx_line: u32,
+                /// The kernel entry or device function, e.g. `"add_f32"`.
+                pub kernel: &'static str,
+                /// The Rust file of the kernel literal, or the kernel file or header, relative to the crate root.
+                pub file: &'static str,
+                pub line: u32,
+            }
+
+            /// Returns the source line of a PTX line in `SOURCE_MAP`, e.g. to report `kernel add_f32, src/ops/add.rs:42`.
+            pub fn source_line(source_map: &'static [SourceLine], ptx_line: u32) -> Option<&'static SourceLine> {
+                let idx = source_map.partition_point(|line| line.ptx_line <= ptx_line);
+                source_map[..idx].last()
+            }
+        },
+        false => quote!(),
+    };
+    let kernel_struct = quote! {
+        /// Metadata of a kernel entry point.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct Kernel {
+            /// The (possibly mangled) name of the entry point.
+            pub name: &'static str,
+            /// The PTX types of the parameters, e.g. `"u64"` for a pointer.
+            pub params: &'static [&'static str],
+            /// Registers per thread, if `resource_usage = true`.
+            pub registers: Option<u32>,
+            /// Statically allocated shared memory, if `resource_usage = true`.
+            pub shared_mem_bytes: Option<u32>,
+        }
+
+        #source_line_struct
+        #launch_config
+    };
+
+    if input.types.is_empty() {
+        let items = instance_items(
+            input,
+            &input.kernel.src,
+            &input.options,
+            compiler,
+            compiler.name(),
+        );
+        return quote! {
+            #[allow(dead_code)]
+            pub mod #module {
+                #[allow(unused_imports)]
+                use super::*;
+
+                #kernel_struct
+                #items
+            }
+        };
+    }
+
+    let (lookup, submodules): (Vec<_>, Vec<_>) = instances(
+        &input.types,
+        &input.kernel.src,
+        &input.options,
+        compiler.name(),
+    )
+    .map(|instance| {
+        let ty = instance.ty;
+        let items = instance_items(
+            input,
+            &instance.src,
+            &instance.options,
+            compiler,
+            &instance.name,
+        );
+
+        let submodule = format_ident!("{}", type_name(ty).rsplit("::").next().unwrap_or_default());
+        (
+            quote! {
+                if id == core::any::TypeId::of::<#ty>() {
+                    return Some(#submodule::ptx());
+                }
+            },
+            quote! {
+                pub mod #submodule {
+                    #[allow(unused_imports)]
+                    use super::*;
+                    #items
+                }
+            },
+        )
+    })
+    .unzip();
+
+    quote! {
+        #[allow(dead_code)]
+        pub mod #module {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #kernel_struct
+
+            /// Returns the PTX instantiated for `T`, if `T` was listed in `types`.
+            pub fn ptx<T: 'static>() -> Option<custos::cuda::Ptx> {
+                let id = core::any::TypeId::of::<T>();
+                #(#lookup)*
+                None
+            }
+
+            #(#submodules)*
+        }
+    }
+}
+
+fn instance_items(
+    input: &CudaInput,
+    src: &str,
+    options: &CudaOptions,
+    compiler: &CudaCompiler,
+    name: &str,
+) -> TokenStream {
+    let (value, ptx) = embed_kernel(&input.kernel, src, options, compiler, CudaMode::Ptx, name);
+    let ptx = ptx.unwrap_or_default();
+
+    let (resources, resource_errors) = match input.resource_usage && !ptx.is_empty() {
+        true => match resource_usage(&ptx, options) {
+            Ok(resources) => (Some(resources), quote!()),
+            Err(message) => {
+                let message = format!("custos-macro: {message}");
+                (
+                    None,
+                    quote_spanned!(input.kernel.span()=> compile_error!(#message);),
+                )
+            }
+        },
+        false => (None, quote!()),
+    };
+
+    let launch_fns = launch_fns(&input.kernel, src);
+
+    let source_map = match input.source_map {
+        true => {
+            let source_map = source_map(&input.kernel, &ptx);
+            quote! {
+                /// The `.loc` directives of the PTX, ordered by `ptx_line`.
+                pub const SOURCE_MAP: &[SourceLine] = &[#(#source_map),*];
+            }
+        }
+        false => quote!(),
+    };
+
+    let kernels = parse_entries(&ptx)
+        .into_iter()
+        .map(|entry| {
+            let usage = resources
+                .as_ref()
+                .map(|resources| resources.get(&entry.name).copied().unwrap_or_default());
+            let registers = option_tokens(usage.map(|usage: Resources| usage.registers));
+            let shared_mem_bytes = option_tokens(usage.map(|usage| usage.shared_mem_bytes));
+            let Entry { name, params } = entry;
+
+            let kernel = quote! {
+                Kernel {
+                    name: #name,
+                    params: &[#(#params),*],
+                    registers: #registers,
+                    shared_mem_bytes: #shared_mem_bytes,
+                }
+            };
+            (syn::parse_str::<Ident>(&name.to_uppercase()).ok(), kernel)
+        })
+        .collect::<Vec<_>>();
+
+    let consts = kernels.iter().filter_map(|(ident, kernel)| {
+        let ident = ident.as_ref()?;
+        Some(quote!(pub const #ident: Kernel = #kernel;))
+    });
+    let all = kernels.iter().map(|(ident, kernel)| match ident {
+        Some(ident) => quote!(#ident),
+        None => kernel.clone(),
+    });
+
+    quote! {
+        #resource_errors
+
+        pub fn ptx() -> custos::cuda::Ptx {
+            #value
+        }
+
+        /// All kernel entry points of the module.
+        pub const KERNELS: &[Kernel] = &[#(#all),*];
+
+        #(#consts)*
+
+        #source_map
+
+        #launch_fns
+    }
+}
+
+fn option_tokens(value: Option<u32>) -> TokenStream {
+    match value {
+        Some(value) => quote!(Some(#value)),
+        None => quote!(None),
+    }
+}
@@ -0,0 +1,170 @@
+use custos_macro_build::c_lexer::replace_idents;
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use syn::Type;
+
+use crate::options::CudaOptions;
+
+/// The placeholder that is replaced by the C type of each instantiation.
+pub const TYPE_PLACEHOLDER: &str = "T";
+
+/// The C counterpart of a Rust datatype.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CType {
+    pub name: &'static str,
+    /// A header that has to be included to use the type.
+    pub header: Option<&'static str>,
+}
+
+/// Rust primitives and their C types.
+const PRIMITIVES: &[(&str, &str)] = &[
+    ("f32", "float"),
+    ("f64", "double"),
+    ("i8", "signed char"),
+    ("u8", "unsigned char"),
+    ("i16", "short"),
+    ("u16", "unsigned short"),
+    ("i32", "int"),
+    ("u32", "unsigned int"),
+    ("i64", "long long"),
+    ("u64", "unsigned long long"),
+    ("isize", "ptrdiff_t"),
+    ("usize", "size_t"),
+    ("bool", "bool"),
+];
+
+const fn c_type(name: &'static str) -> CType {
+    CType { name, header: None }
+}
+
+/// Returns the C type for a Rust datatype, e.g. `float` for `f32`.
+pub fn c_type_of(ty: &Type) -> Option<CType> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let ident = path.path.segments.last()?.ident.to_string();
+
+    Some(match ident.as_str() {
+        "f16" => CType {
+            name: "__half",
+            header: Some("cuda_fp16.h"),
+        },
+        "bf16" => CType {
+            name: "__nv_bfloat16",
+            header: Some("cuda_bf16.h"),
+        },
+        ident => PRIMITIVES
+            .iter()
+            .find(|(rust, _)| *rust == ident)
+            .map(|(_, c)| c_type(c))?,
+    })
+}
+
+/// Returns the Rust primitive for a C type, e.g. `f32` for `float` or `u32` for `unsigned`.
+pub fn rust_type_of(c_type: &str) -> Option<&'static str> {
+    let c_type = match c_type {
+        "char" | "int8_t" => "signed char",
+        "uint8_t" => "unsigned char",
+        "short int" | "signed short" | "int16_t" => "short",
+        "unsigned short int" | "uint16_t" => "unsigned short",
+        "signed" | "signed int" | "int32_t" => "int",
+        "unsigned" | "uint32_t" => "unsigned int",
+        "long long int" | "signed long long" | "int64_t" => "long long",
+        "unsigned long long int" | "uint64_t" => "unsigned long long",
+        c_type => c_type,
+    };
+    PRIMITIVES
+        .iter()
+        .find(|(_, c)| *c == c_type)
+        .map(|(rust, _)| *rust)
+}
+
+/// Returns the Rust datatype for an OpenCL C type, e.g. `u32` for `uint`.
+/// `long` is always 64 bits wide in OpenCL C.
+pub fn rust_type_of_opencl(c_type: &str) -> Option<&'static str> {
+    match c_type {
+        "char" => Some("i8"),
+        "uchar" => Some("u8"),
+        "ushort" => Some("u16"),
+        "uint" => Some("u32"),
+        "long" => Some("i64"),
+        "ulong" | "unsigned long" => Some("u64"),
+        // `size_t`, `ptrdiff_t` and `bool` are not allowed as kernel arguments
+        "size_t" | "ptrdiff_t" | "bool" => None,
+        c_type => rust_type_of(c_type),
+    }
+}
+
+/// Replaces every `T` identifier outside of comments and literals with the C type.
+pub fn instantiate(src: &str, c_type: CType) -> String {
+    replace_idents(src, |ident| {
+        (ident == TYPE_PLACEHOLDER).then(|| c_type.name.to_string())
+    })
+}
+
+/// A kernel instantiated for one of the `types` of `cuda!` or `hip!`.
+pub struct Instance<'a> {
+    pub ty: &'a Type,
+    pub src: String,
+    /// The options, with the header of the C type pre-included.
+    pub options: CudaOptions,
+    /// The name for diagnostics, e.g. `nvcc (T = f32)`.
+    pub name: String,
+}
+
+/// Instantiates `src` for every datatype of `types`, `compiler` is the prefix of the diagnostics name.
+pub fn instances<'a>(
+    types: &'a [(Type, CType)],
+    src: &'a str,
+    options: &'a CudaOptions,
+    compiler: &'a str,
+) -> impl Iterator<Item = Instance<'a>> + 'a {
+    types.iter().map(move |(ty, c_type)| {
+        let mut options = options.clone();
+        if let Some(header) = c_type.header {
+            options.flags.push(format!("--pre-include={header}"));
+        }
+        Instance {
+            ty,
+            src: instantiate(src, *c_type),
+            options,
+            name: format!("{compiler} (T = {})", type_name(ty)),
+        }
+    })
+}
+
+/// Expands to a `KernelsByType` value, whose `get::<T>()` returns the `output` of `T`.
+/// `kernels` are the expressions of the listed datatypes.
+pub fn kernels_by_type<'a>(
+    output: TokenStream,
+    kernels: impl IntoIterator<Item = (&'a Type, TokenStream)>,
+) -> TokenStream {
+    let lookup = kernels.into_iter().map(|(ty, kernel)| {
+        quote! {
+            if id == core::any::TypeId::of::<#ty>() {
+                return Some(#kernel);
+            }
+        }
+    });
+
+    quote! {
+        struct KernelsByType;
+
+        #[allow(dead_code)]
+        impl KernelsByType {
+            /// Returns the kernel instantiated for `T`, if `T` was listed in `types`.
+            fn get<T: 'static>(&self) -> Option<#output> {
+                let id = core::any::TypeId::of::<T>();
+                #(#lookup)*
+                None
+            }
+        }
+
+        KernelsByType
+    }
+}
+
+/// A readable name of the type for diagnostics, e.g. `half::f16`.
+pub fn type_name(ty: &Type) -> String {
+    ty.to_token_stream().to_string().replace(' ', "")
+}
@@ -0,0 +1,134 @@
+use custos_macro_build::{
+    cuda::CompileError,
+    hip::{compile_code_object, HipCompiler, HIP_ENV_VARS},
+    hipify::hipify,
+};
+use proc_macro2::{Literal, TokenStream};
+use quote::{quote, quote_spanned};
+
+use crate::{
+    dtype::{instances, kernels_by_type},
+    kernel_src::KernelSrc,
+    options::{track_env_vars, CudaInput, CudaMode, CudaOptions, Target, CUDA_ENV_VARS},
+};
+
+/// Expands `hip!`.
+pub fn hip_expansion(input: TokenStream) -> TokenStream {
+    match CudaInput::parse(input, false) {
+        Ok(mut input) => {
+            input.target = Target::Hip;
+            hip_kernels(&input)
+        }
+        Err(err) => err.to_compile_error(),
+    }
+}
+
+/// Expands `hip!` or `cuda!(target = "hip", ...)`.
+///
+/// The kernel is translated with [`hipify`]. It expands to a `custos::hip::CodeObject` compiled with `CUSTOS_HIP_COMPILER`,
+/// or to a `custos::hip::HipSrc` with the translated source, if no compiler is configured or in runtime mode.
+pub fn hip_kernels(input: &CudaInput) -> TokenStream {
+    let mut tracked = track_env_vars(CUDA_ENV_VARS);
+    tracked.extend(track_env_vars(HIP_ENV_VARS));
+    tracked.extend(input.kernel.track_files());
+
+    if let Some(module) = &input.module {
+        return quote_spanned!(module.span()=> compile_error!("`module` is not available for the HIP target."));
+    }
+    if input.resource_usage {
+        return quote_spanned!(input.kernel.span()=> compile_error!("`resource_usage` is not available for the HIP target."));
+    }
+    if input.source_map {
+        return quote_spanned!(input.kernel.span()=> compile_error!("`source_map` is not available for the HIP target."));
+    }
+
+    let mode = match CudaMode::from_env() {
+        Ok(mode) => mode,
+        Err(message) => {
+            let message = format!("custos-macro: {message}");
+            return quote_spanned!(input.kernel.span()=> compile_error!(#message));
+        }
+    };
+    let compiler = match mode {
+        CudaMode::Ptx => HipCompiler::from_env(&input.options),
+        CudaMode::Runtime => None,
+    };
+
+    let output = match compiler {
+        Some(_) => quote!(custos::hip::CodeObject),
+        None => quote!(custos::hip::HipSrc),
+    };
+
+    if input.types.is_empty() {
+        let kernel = embed_hip(
+            &input.kernel,
+            &input.kernel.src,
+            &input.options,
+            compiler.as_ref(),
+            "hipcc",
+        );
+        return quote!({
+            #tracked
+            #kernel
+        });
+    }
+
+    let kernels =
+        instances(&input.types, &input.kernel.src, &input.options, "hipcc").map(|instance| {
+            let kernel = embed_hip(
+                &input.kernel,
+                &instance.src,
+                &instance.options,
+                compiler.as_ref(),
+                &instance.name,
+            );
+            (instance.ty, kernel)
+        });
+    let kernels = kernels_by_type(output, kernels);
+
+    quote!({
+        #tracked
+        #kernels
+    })
+}
+
+fn embed_hip(
+    kernel: &KernelSrc,
+    src: &str,
+    options: &CudaOptions,
+    compiler: Option<&HipCompiler>,
+    name: &str,
+) -> TokenStream {
+    let Some(compiler) = compiler else {
+        // without a compiler, the defines and pre-included headers become part of the source
+        let src = hipify(&(options.source_preamble() + src));
+        return quote! {
+            custos::hip::HipSrc {
+                src: #src.to_string(),
+            }
+        };
+    };
+    let src = hipify(src);
+
+    let (diagnostics, bin) = match compile_code_object(&src, &kernel.includes, options, compiler) {
+        Ok((bin, diagnostics)) => (kernel.emit_diagnostics(name, &diagnostics), bin),
+        Err(CompileError::Io(message)) => {
+            let message = format!("custos-macro: {message}");
+            (
+                quote_spanned!(kernel.span()=> compile_error!(#message);),
+                Vec::new(),
+            )
+        }
+        Err(CompileError::Diagnostics(diagnostics)) => {
+            (kernel.emit_diagnostics(name, &diagnostics), Vec::new())
+        }
+    };
+
+    let bin = Literal::byte_string(&bin);
+    quote!({
+        #diagnostics
+        custos::hip::CodeObject {
+            bin: #bin.to_vec(),
+        }
+    })
+}
@@ -0,0 +1,58 @@
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned};
+
+use crate::{kernel_src::KernelSrc, options::CudaOptions};
+
+/// Expands to a `custos_macro_build::HotReload` of `kernel`, which has to be read from a file.
+///
+/// Debug builds reload the file, `reload` is a `custos_macro_build::Reload`.
+/// Release builds only contain the `embedded` PTX or source.
+pub fn hot_reload_handle(kernel: &KernelSrc, embedded: &str, reload: TokenStream) -> TokenStream {
+    let Some(path) = kernel.path() else {
+        return quote_spanned!(kernel.span()=> compile_error!(
+            "`hot_reload` needs a kernel file, e.g. `path = \"kernels/add.cu\"`."
+        ));
+    };
+    let path = path.display().to_string();
+
+    quote!({
+        #[cfg(debug_assertions)]
+        let kernel = ::custos_macro_build::HotReload::new(#path, #embedded, #reload);
+        #[cfg(not(debug_assertions))]
+        let kernel = ::custos_macro_build::HotReload::embedded(#embedded);
+        kernel
+    })
+}
+
+/// The options as a `custos_macro_build::Reload::Ptx`, so a reloaded kernel is compiled like the embedded one.
+pub fn reload_ptx(options: &CudaOptions) -> TokenStream {
+    let CudaOptions {
+        arch,
+        opt_level,
+        defines,
+        include_dirs,
+        flags,
+    } = options;
+
+    let string = |value: &Option<String>| match value {
+        Some(value) => quote!(Some(#value.to_string())),
+        None => quote!(None),
+    };
+    let arch = string(arch);
+    let opt_level = string(opt_level);
+    let defines = defines.iter().map(|(name, value)| {
+        let value = string(value);
+        quote!((#name.to_string(), #value))
+    });
+    let include_dirs = include_dirs.iter().map(|dir| dir.display().to_string());
+
+    quote! {
+        ::custos_macro_build::Reload::Ptx(::custos_macro_build::CudaOptions {
+            arch: #arch,
+            opt_level: #opt_level,
+            defines: vec![#(#defines),*],
+            include_dirs: vec![#(std::path::PathBuf::from(#include_dirs)),*],
+            flags: vec![#(#flags.to_string()),*],
+        })
+    }
+}
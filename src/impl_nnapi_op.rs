@@ -43,7 +43,7 @@ pub fn add_nnapi_op_impl(
     let mut output_generic = "S";
 
     let rhs_generics = extract_rhs_generics_to_len(&input.generics, type_params_len - 1, |ident| {
-        if ident.to_string() == "OS" {
+        if *ident == "OS" {
             output_generic = "OS";
         }
     });
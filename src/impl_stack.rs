@@ -0,0 +1,1271 @@
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::{quote, ToTokens};
+use syn::{
+    parenthesized,
+    parse::{Parse, ParseStream},
+    parse_quote,
+    punctuated::Punctuated,
+    spanned::Spanned,
+    visit_mut::{self, VisitMut},
+    Attribute, Block, Expr, ExprPath, GenericArgument, GenericParam, ImplItem, ItemImpl, LitStr,
+    Meta, Path, PathArguments, Token, Type, TypePath, WherePredicate,
+};
+
+/// The condition an impl block is compiled under.
+pub enum Cfg {
+    /// `#[cfg(feature = "...")]`
+    Feature(LitStr),
+    /// `#[cfg(...)]` with any predicate, e.g. `all(feature = "cpu", not(target_os = "none"))`.
+    Predicate(TokenStream),
+    /// No `#[cfg]`, the impl block is always compiled.
+    Always,
+}
+
+impl Cfg {
+    pub fn feature(name: &str) -> Cfg {
+        Cfg::Feature(LitStr::new(name, Span::call_site()))
+    }
+}
+
+impl ToTokens for Cfg {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        tokens.extend(match self {
+            Cfg::Feature(feature) => quote!(#[cfg(feature = #feature)]),
+            Cfg::Predicate(predicate) => quote!(#[cfg(#predicate)]),
+            Cfg::Always => quote!(),
+        });
+    }
+}
+
+/// A device of `#[impl_for_devices]`: `Stack`, `custos::OpenCL => "opencl"` or `custos::OpenCL => cfg(...)`.
+pub struct TargetDevice {
+    pub device: Path,
+    /// The condition the copy is compiled under, by default the feature named after the device.
+    pub cfg: Cfg,
+}
+
+impl TargetDevice {
+    /// The feature named after the device in snake case, e.g. `my_cpu_wrapper` for `MyCpuWrapper`.
+    fn default_cfg(device: &Path) -> Cfg {
+        let name = device.segments.last().map(|segment| &segment.ident);
+        let feature = name.map(|name| snake_case(&name.to_string()));
+        Cfg::Feature(LitStr::new(
+            &feature.unwrap_or_default(),
+            name.map_or(Span::call_site(), Ident::span),
+        ))
+    }
+
+    /// The condition after `=>`, a feature or `cfg(...)`.
+    fn parse_cfg(input: ParseStream) -> syn::Result<Option<Cfg>> {
+        if !input.peek(Token![=>]) {
+            return Ok(None);
+        }
+
+        input.parse::<Token![=>]>()?;
+        let cfg = match input.peek(LitStr) {
+            true => Cfg::Feature(input.parse()?),
+            false => {
+                let cfg: Ident = input.parse()?;
+                if cfg != "cfg" {
+                    return Err(syn::Error::new(
+                        cfg.span(),
+                        "expected a feature (`\"opencl\"`) or a cfg predicate (`cfg(...)`)",
+                    ));
+                }
+                let content;
+                parenthesized!(content in input);
+                Cfg::Predicate(content.parse::<Meta>()?.into_token_stream())
+            }
+        };
+        Ok(Some(cfg))
+    }
+}
+
+/// The options of `#[impl_for_devices]`: the devices and the condition of the `CPU` impl.
+///
+/// `cpu_feature = "..."` and `cpu_cfg = ...` set the condition of the `CPU` impl like for `#[impl_stack]`,
+/// `no_cfg` compiles the `CPU` impl and the devices without `=>` unconditionally.
+pub struct ImplForDevicesArgs {
+    pub cpu: Cfg,
+    pub devices: Vec<TargetDevice>,
+}
+
+impl Parse for ImplForDevicesArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut cpu = None;
+        let mut no_cfg = None;
+        let mut devices = Vec::new();
+
+        while !input.is_empty() {
+            // `cpu_feature = ...`, but not a device with `=>`
+            let is_option =
+                input.peek(syn::Ident) && input.peek2(Token![=]) && !input.peek2(Token![=>]);
+            let is_no_cfg = {
+                let fork = input.fork();
+                fork.parse::<Ident>().is_ok_and(|key| key == "no_cfg")
+                    && (fork.is_empty() || fork.peek(Token![,]))
+            };
+
+            if is_no_cfg {
+                no_cfg = Some(input.parse::<Ident>()?);
+            } else if is_option {
+                let key: Ident = input.parse()?;
+                input.parse::<Token![=]>()?;
+                let cfg = match key.to_string().as_str() {
+                    "cpu_feature" => Cfg::Feature(input.parse()?),
+                    "cpu_cfg" => Cfg::Predicate(input.parse::<Meta>()?.into_token_stream()),
+                    _ => {
+                        return Err(syn::Error::new(
+                            key.span(),
+                            "unknown option, expected one of `cpu_feature`, `cpu_cfg`, `no_cfg` or a device",
+                        ))
+                    }
+                };
+                if cpu.replace(cfg).is_some() {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "the condition of the `CPU` impl block is already set",
+                    ));
+                }
+            } else {
+                let device: Path = input.parse()?;
+                let cfg = TargetDevice::parse_cfg(input)?;
+                devices.push((device, cfg));
+            }
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+
+        if devices.is_empty() {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "expected a list of devices, e.g. `Stack, custos::OpenCL => \"opencl\"`",
+            ));
+        }
+        if let Some(no_cfg) = &no_cfg {
+            if cpu.is_some() || devices.iter().any(|(_, cfg)| cfg.is_some()) {
+                return Err(syn::Error::new(
+                    no_cfg.span(),
+                    "`no_cfg` cannot be combined with other conditions",
+                ));
+            }
+        }
+
+        let devices = devices
+            .into_iter()
+            .map(|(device, cfg)| {
+                let cfg = match (cfg, &no_cfg) {
+                    (Some(cfg), _) => cfg,
+                    (None, Some(_)) => Cfg::Always,
+                    (None, None) => TargetDevice::default_cfg(&device),
+                };
+                TargetDevice { device, cfg }
+            })
+            .collect::<Vec<_>>();
+
+        let features = devices
+            .iter()
+            .filter_map(|target| match &target.cfg {
+                Cfg::Feature(feature) => Some(feature),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for (idx, feature) in features.iter().enumerate() {
+            if features[..idx]
+                .iter()
+                .any(|other| other.value() == feature.value())
+            {
+                return Err(syn::Error::new(
+                    feature.span(),
+                    format!("the feature `{}` is used for two devices", feature.value()),
+                ));
+            }
+        }
+
+        let cpu = match no_cfg {
+            Some(_) => Cfg::Always,
+            None => cpu.unwrap_or_else(|| Cfg::feature("cpu")),
+        };
+        Ok(ImplForDevicesArgs { cpu, devices })
+    }
+}
+
+/// The options of `#[impl_stack]`: the conditions of the `CPU` impl and the `Stack` copy.
+///
+/// `cpu_feature = "..."` and `stack_feature = "..."` set the features, `cpu_cfg = ...` and `stack_cfg = ...`
+/// any cfg predicate, `no_cfg` compiles both unconditionally.
+/// `const_n` adds the array length `N` to the `Stack` copy, see [`insert_const_n`].
+pub struct ImplStackArgs {
+    pub cpu: Cfg,
+    pub stack: Cfg,
+    pub const_n: bool,
+}
+
+impl Parse for ImplStackArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut cpu = None;
+        let mut stack = None;
+        let mut no_cfg = None;
+        let mut const_n = false;
+
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            if key == "no_cfg" {
+                no_cfg = Some(key);
+            } else if key == "const_n" {
+                const_n = true;
+            } else {
+                input.parse::<Token![=]>()?;
+                let (cfg, slot) = match key.to_string().as_str() {
+                    "cpu_feature" => (Cfg::Feature(input.parse()?), &mut cpu),
+                    "stack_feature" => (Cfg::Feature(input.parse()?), &mut stack),
+                    "cpu_cfg" => (Cfg::Predicate(input.parse::<Meta>()?.into_token_stream()), &mut cpu),
+                    "stack_cfg" => (Cfg::Predicate(input.parse::<Meta>()?.into_token_stream()), &mut stack),
+                    _ => {
+                        return Err(syn::Error::new(
+                            key.span(),
+                            "unknown option, expected one of `cpu_feature`, `stack_feature`, `cpu_cfg`, `stack_cfg`, `no_cfg`, `const_n`",
+                        ))
+                    }
+                };
+                if slot.replace(cfg).is_some() {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "the condition of this impl block is already set",
+                    ));
+                }
+            }
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+
+        if let Some(no_cfg) = no_cfg {
+            if cpu.is_some() || stack.is_some() {
+                return Err(syn::Error::new(
+                    no_cfg.span(),
+                    "`no_cfg` cannot be combined with other conditions",
+                ));
+            }
+            return Ok(ImplStackArgs {
+                cpu: Cfg::Always,
+                stack: Cfg::Always,
+                const_n,
+            });
+        }
+
+        Ok(ImplStackArgs {
+            cpu: cpu.unwrap_or_else(|| Cfg::feature("cpu")),
+            stack: stack.unwrap_or_else(|| Cfg::feature("stack")),
+            const_n,
+        })
+    }
+}
+
+/// Expands `#[impl_stack]`: the impl block for `CPU` and a copy for `Stack`.
+///
+/// The per-method attributes `#[stack(...)]`, `#[stack_only]` and `#[cpu_only]` are applied, see [`StackAttrs`].
+pub fn impl_stack_expansion(args: ImplStackArgs, mut impl_block: ItemImpl) -> TokenStream {
+    let ImplStackArgs {
+        cpu,
+        stack,
+        const_n,
+    } = args;
+    let (impl_attrs, item_attrs) = match take_stack_attrs(&mut impl_block) {
+        Ok(attrs) => attrs,
+        Err(err) => return err.to_compile_error(),
+    };
+    let device = Ident::new("Stack", Span::call_site()).into();
+    let mut stack_impl_block = device_impl(&impl_block, &device);
+
+    if const_n {
+        if let Err(err) = insert_const_n(&mut stack_impl_block, &device) {
+            return err.to_compile_error();
+        }
+    }
+
+    let items = std::mem::take(&mut impl_block.items);
+    impl_block.items = items
+        .into_iter()
+        .zip(&item_attrs)
+        .filter(|(_, attrs)| attrs.only != Some(Only::Stack))
+        .map(|(item, _)| item)
+        .collect();
+    apply_stack_attrs(&mut stack_impl_block, impl_attrs, item_attrs);
+
+    quote!(
+        #cpu
+        #impl_block
+
+        #stack
+        #stack_impl_block
+    )
+}
+
+/// An item that is only part of one copy, `#[cpu_only]` or `#[stack_only]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Only {
+    Cpu,
+    Stack,
+}
+
+/// The `#[impl_stack]` attributes of an impl block or one of its items:
+///
+/// - `#[stack(skip)]`: the item is left out of the `Stack` copy, e.g. to use the default method of the trait
+/// - `#[stack(body = { ... })]`: the method body of the `Stack` copy
+/// - `#[stack(where T: Copy, ...)]`: bounds that are only added to the `Stack` copy, on the impl block
+///   or on a method of an inherent impl (a trait method cannot have stricter bounds than the trait)
+/// - `#[cpu_only]`, `#[stack_only]`: the item is only part of one copy, e.g. two versions of a method
+///
+/// Several options can be combined in one attribute, `where` has to come last: `#[stack(body = ..., where T: Copy)]`.
+#[derive(Default)]
+struct StackAttrs {
+    skip: bool,
+    body: Option<Block>,
+    predicates: Vec<WherePredicate>,
+    /// The span of the `where` token.
+    where_span: Option<Span>,
+    only: Option<Only>,
+}
+
+impl StackAttrs {
+    /// Removes the `#[impl_stack]` attributes from `attrs`.
+    fn take(attrs: &mut Vec<Attribute>) -> syn::Result<StackAttrs> {
+        let mut stack_attrs = StackAttrs::default();
+        let mut only_span = None;
+
+        for attr in std::mem::take(attrs) {
+            let path = attr.path();
+            if path.is_ident("cpu_only") || path.is_ident("stack_only") {
+                attr.meta.require_path_only()?;
+                let only = match path.is_ident("cpu_only") {
+                    true => Only::Cpu,
+                    false => Only::Stack,
+                };
+                if stack_attrs
+                    .only
+                    .replace(only)
+                    .is_some_and(|other| other != only)
+                {
+                    return Err(syn::Error::new(
+                        attr.span(),
+                        "an item cannot be both `#[cpu_only]` and `#[stack_only]`",
+                    ));
+                }
+                only_span = Some(attr.span());
+            } else if path.is_ident("stack") {
+                attr.parse_args_with(|input: ParseStream| stack_attrs.parse_options(input))?;
+            } else {
+                attrs.push(attr);
+            }
+        }
+
+        if let (Some(span), true) = (only_span, stack_attrs.skip || stack_attrs.body.is_some()) {
+            return Err(syn::Error::new(
+                span,
+                "`#[stack(...)]` cannot be combined with `#[cpu_only]` or `#[stack_only]`",
+            ));
+        }
+        Ok(stack_attrs)
+    }
+
+    /// `skip`, `body = ...` and `where ...`, separated by commas.
+    fn parse_options(&mut self, input: ParseStream) -> syn::Result<()> {
+        while !input.is_empty() {
+            if input.peek(Token![where]) {
+                let where_token = input.parse::<Token![where]>()?;
+                self.where_span = Some(where_token.span);
+                let predicates = Punctuated::<WherePredicate, Token![,]>::parse_terminated(input)?;
+                self.predicates.extend(predicates);
+                break;
+            }
+
+            let key: Ident = input.parse()?;
+            match key.to_string().as_str() {
+                "skip" => self.skip = true,
+                "body" => {
+                    input.parse::<Token![=]>()?;
+                    self.body = Some(match input.parse()? {
+                        Expr::Block(block) => block.block,
+                        expr => parse_quote!({ #expr }),
+                    });
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "unknown option, expected one of `skip`, `body`, `where`",
+                    ))
+                }
+            }
+            if self.skip && self.body.is_some() {
+                return Err(syn::Error::new(
+                    key.span(),
+                    "`skip` cannot be combined with `body`",
+                ));
+            }
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes the `#[impl_stack]` attributes of the impl block and its items.
+/// Returns the attributes of the impl block and of every item, in the order of the items.
+fn take_stack_attrs(impl_block: &mut ItemImpl) -> syn::Result<(StackAttrs, Vec<StackAttrs>)> {
+    let impl_attrs = StackAttrs::take(&mut impl_block.attrs)?;
+    if impl_attrs.skip || impl_attrs.body.is_some() || impl_attrs.only.is_some() {
+        return Err(syn::Error::new(
+            impl_block.impl_token.span,
+            "only `#[stack(where ...)]` can be used on the impl block",
+        ));
+    }
+
+    let is_trait_impl = impl_block.trait_.is_some();
+    let item_attrs = impl_block
+        .items
+        .iter_mut()
+        .map(|item| {
+            let (attrs, is_fn) = match item {
+                ImplItem::Fn(method) => (&mut method.attrs, true),
+                ImplItem::Const(item) => (&mut item.attrs, false),
+                ImplItem::Type(item) => (&mut item.attrs, false),
+                ImplItem::Macro(item) => (&mut item.attrs, false),
+                _ => return Ok(StackAttrs::default()),
+            };
+            let item_attrs = StackAttrs::take(attrs)?;
+            if !is_fn && (item_attrs.body.is_some() || !item_attrs.predicates.is_empty()) {
+                return Err(syn::Error::new(
+                    item.span(),
+                    "`#[stack(body = ...)]` and `#[stack(where ...)]` can only be used on methods",
+                ));
+            }
+            if let (Some(span), true) = (item_attrs.where_span, is_trait_impl) {
+                return Err(syn::Error::new(
+                    span,
+                    "`#[stack(where ...)]` cannot be used on a method of a trait impl, \
+                    its bounds would be stricter than the trait's; use `#[stack(where ...)]` on the impl block instead",
+                ));
+            }
+            Ok(item_attrs)
+        })
+        .collect::<syn::Result<_>>()?;
+
+    Ok((impl_attrs, item_attrs))
+}
+
+/// Applies the attributes to the `Stack` copy, whose items are still in the order of `item_attrs`.
+fn apply_stack_attrs(
+    stack_impl_block: &mut ItemImpl,
+    impl_attrs: StackAttrs,
+    item_attrs: Vec<StackAttrs>,
+) {
+    if !impl_attrs.predicates.is_empty() {
+        let where_clause = stack_impl_block.generics.make_where_clause();
+        where_clause.predicates.extend(impl_attrs.predicates);
+    }
+
+    let items = std::mem::take(&mut stack_impl_block.items);
+    stack_impl_block.items = items
+        .into_iter()
+        .zip(item_attrs)
+        .filter(|(_, attrs)| !attrs.skip && attrs.only != Some(Only::Cpu))
+        .map(|(mut item, attrs)| {
+            if let ImplItem::Fn(method) = &mut item {
+                if let Some(body) = attrs.body {
+                    method.block = body;
+                }
+                if !attrs.predicates.is_empty() {
+                    let where_clause = method.sig.generics.make_where_clause();
+                    where_clause.predicates.extend(attrs.predicates);
+                }
+            }
+            item
+        })
+        .collect();
+}
+
+/// Expands `#[impl_for_devices]`: the impl block for `CPU` under the `cpu` condition
+/// and a copy per device under the condition of the device.
+pub fn impl_for_devices_expansion(args: ImplForDevicesArgs, impl_block: ItemImpl) -> TokenStream {
+    let ImplForDevicesArgs { cpu, devices } = args;
+    let copies = devices.iter().map(|TargetDevice { device, cfg }| {
+        let device_impl_block = device_impl(&impl_block, device);
+        quote! {
+            #cfg
+            #device_impl_block
+        }
+    });
+
+    quote!(
+        #cpu
+        #impl_block
+
+        #(#copies)*
+    )
+}
+
+/// A copy of the impl block with `CPU` replaced by `device`.
+fn device_impl(impl_block: &ItemImpl, device: &Path) -> ItemImpl {
+    let mut device_impl_block = impl_block.clone();
+    ReplaceCpu { device }.visit_item_impl_mut(&mut device_impl_block);
+    device_impl_block
+}
+
+/// Adds the array length of stack allocated buffers to an impl block, e.g.
+///
+/// ```ignore
+/// impl<T: Number, S: Shape> ElementWise<T, Stack, S> for Stack {
+///     fn add(&self, lhs: &Buffer<T, Stack, S>, rhs: &Buffer<T, Stack, S>) -> Buffer<T, Stack, S>;
+/// }
+/// ```
+///
+/// becomes
+///
+/// ```ignore
+/// impl<T: Number, S: Shape, const N: usize> ElementWise<T, Stack, S, N> for Stack
+/// where
+///     Stack: custos::Alloc<T, N>,
+/// {
+///     fn add(&self, lhs: &Buffer<T, Stack, S, N>, rhs: &Buffer<T, Stack, S, N>) -> Buffer<T, Stack, S, N>;
+/// }
+/// ```
+///
+/// `T` is the first type parameter. `N` is appended to the trait, which constrains it, and to every `Buffer`
+/// whose device argument is `device`, in the trait arguments, the where clauses, the method signatures and bodies,
+/// also nested ones like `Option<(Buffer<..>, usize)>` and `Buffer::<T, Stack, S>::new(..)`.
+/// Buffers of other devices and buffers inside macro invocations are left as they are.
+pub fn insert_const_n(impl_block: &mut ItemImpl, device: &Path) -> syn::Result<()> {
+    let span = impl_block.self_ty.span();
+    let Some(elem) = impl_block
+        .generics
+        .type_params()
+        .next()
+        .map(|param| param.ident.clone())
+    else {
+        return Err(syn::Error::new(
+            span,
+            "`const_n` needs the datatype as first type parameter, e.g. `impl<T, S: Shape> ...`",
+        ));
+    };
+    let Some((_, trait_path, _)) = &mut impl_block.trait_ else {
+        return Err(syn::Error::new(
+            span,
+            "`const_n` needs a trait impl, `N` is passed to the trait as last generic argument",
+        ));
+    };
+    let n = Ident::new("N", Span::call_site());
+    if impl_block.generics.params.iter().any(|param| match param {
+        GenericParam::Type(param) => param.ident == n,
+        GenericParam::Const(param) => param.ident == n,
+        GenericParam::Lifetime(_) => false,
+    }) {
+        return Err(syn::Error::new(
+            impl_block.generics.span(),
+            "`const_n` adds a generic parameter `N`, which is already declared",
+        ));
+    }
+
+    let mut insert_n = InsertConstN { n: &n, device };
+    insert_n.visit_path_mut(trait_path);
+    if let Some(last) = trait_path.segments.last_mut() {
+        match &mut last.arguments {
+            PathArguments::AngleBracketed(args) => args.args.push(parse_quote!(#n)),
+            arguments => *arguments = PathArguments::AngleBracketed(parse_quote!(<#n>)),
+        }
+    }
+
+    if let Some(where_clause) = &mut impl_block.generics.where_clause {
+        insert_n.visit_where_clause_mut(where_clause);
+    }
+    for item in &mut impl_block.items {
+        insert_n.visit_impl_item_mut(item);
+    }
+
+    impl_block
+        .generics
+        .params
+        .push(parse_quote!(const #n: usize));
+    impl_block
+        .generics
+        .make_where_clause()
+        .predicates
+        .push(parse_quote!(#device: custos::Alloc<#elem, #n>));
+    Ok(())
+}
+
+/// Appends `N` to the generic arguments of every `Buffer` of `device`, in types and in expression paths.
+struct InsertConstN<'a> {
+    n: &'a Ident,
+    device: &'a Path,
+}
+
+impl InsertConstN<'_> {
+    /// `Stack` or a path ending in it, e.g. `custos::Stack`.
+    fn is_device(&self, arg: &GenericArgument) -> bool {
+        let GenericArgument::Type(Type::Path(TypePath { qself: None, path })) = arg else {
+            return false;
+        };
+        path.segments.last().map(|segment| &segment.ident)
+            == self.device.segments.last().map(|segment| &segment.ident)
+    }
+}
+
+impl VisitMut for InsertConstN<'_> {
+    fn visit_path_mut(&mut self, path: &mut Path) {
+        visit_mut::visit_path_mut(self, path);
+
+        for segment in &mut path.segments {
+            let PathArguments::AngleBracketed(args) = &mut segment.arguments else {
+                continue;
+            };
+            if segment.ident == "Buffer" && args.args.iter().any(|arg| self.is_device(arg)) {
+                let n = self.n;
+                args.args.push(parse_quote!(#n));
+            }
+        }
+    }
+}
+
+/// Replaces the `CPU` device in type paths with another device, e.g. `Buffer<T, CPU, S>` or `custos::CPU`.
+///
+/// Only whole path segments named `CPU` are replaced, so identifiers like `CPUBuffer` or `MAX_CPU_THREADS`,
+/// string literals and macro invocations stay as they are.
+/// A device name replaces the segment (`custos::CPU` -> `custos::Stack`), a device path replaces the path up to
+/// the segment (`custos::CPU` -> `custos::OpenCL`). Generic arguments of `CPU` are kept if the device has none.
+/// The new segments keep the span of `CPU`, errors in the copy point at the original line.
+struct ReplaceCpu<'a> {
+    device: &'a Path,
+}
+
+impl ReplaceCpu<'_> {
+    /// Replaces the first segment named `CPU` among the first `len` segments.
+    /// In expressions, generic arguments of the device need a turbofish (`MyCpuWrapper::<Base>::new()`).
+    fn replace(&self, path: &mut Path, len: usize, turbofish: bool) {
+        let Some(idx) = path
+            .segments
+            .iter()
+            .take(len)
+            .position(|segment| segment.ident == "CPU")
+        else {
+            return;
+        };
+        let span = path.segments[idx].ident.span();
+        let mut device = self.device.clone();
+        for segment in &mut device.segments {
+            segment.ident.set_span(span);
+            if let (true, PathArguments::AngleBracketed(args)) = (turbofish, &mut segment.arguments)
+            {
+                args.colon2_token.get_or_insert_with(|| Token![::](span));
+            }
+        }
+
+        let cpu = &path.segments[idx];
+        if let Some(last) = device.segments.last_mut() {
+            if last.arguments.is_none() {
+                last.arguments = cpu.arguments.clone();
+            }
+        }
+
+        if device.segments.len() == 1 && device.leading_colon.is_none() {
+            path.segments[idx] = device
+                .segments
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| cpu.clone());
+            return;
+        }
+        device
+            .segments
+            .extend(path.segments.iter().skip(idx + 1).cloned());
+        *path = device;
+    }
+}
+
+impl VisitMut for ReplaceCpu<'_> {
+    // the path is replaced after its arguments, a device like `MyCpuWrapper<CPU>` is not visited again
+    fn visit_type_path_mut(&mut self, type_path: &mut TypePath) {
+        visit_mut::visit_type_path_mut(self, type_path);
+        // the `CPU` of `<CPU as Device>::Base` is visited on its own
+        if type_path.qself.is_none() {
+            let len = type_path.path.segments.len();
+            self.replace(&mut type_path.path, len, false);
+        }
+    }
+
+    /// In expressions, only the type a value is qualified with is replaced, e.g. `CPU::new()` but not a value `CPU`.
+    fn visit_expr_path_mut(&mut self, expr_path: &mut ExprPath) {
+        visit_mut::visit_expr_path_mut(self, expr_path);
+        if expr_path.qself.is_none() {
+            let len = expr_path.path.segments.len();
+            self.replace(&mut expr_path.path, len.saturating_sub(1), true);
+        }
+    }
+}
+
+/// `MyCpuWrapper` -> `my_cpu_wrapper`, `OpenCL` -> `open_cl`
+fn snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut snake = String::new();
+
+    for (idx, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && idx > 0 {
+            let prev = chars[idx - 1];
+            let next_is_lower = chars.get(idx + 1).is_some_and(|next| next.is_lowercase());
+            if prev.is_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_uppercase() && next_is_lower)
+            {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use proc_macro2::{Span, TokenStream};
+    use quote::quote;
+
+    use super::{
+        impl_for_devices_expansion, impl_stack_expansion, snake_case, ImplForDevicesArgs,
+        ImplStackArgs,
+    };
+
+    fn impl_stack(args: TokenStream, item: TokenStream) -> String {
+        let args = syn::parse2::<ImplStackArgs>(args).unwrap();
+        impl_stack_expansion(args, syn::parse2(item).unwrap()).to_string()
+    }
+
+    /// The message of an error in the arguments, or the `compile_error!` of the expansion.
+    fn impl_stack_error(args: TokenStream, item: TokenStream) -> String {
+        match syn::parse2::<ImplStackArgs>(args) {
+            Ok(args) => impl_stack_expansion(args, syn::parse2(item).unwrap()).to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn impl_for_devices(args: TokenStream, item: TokenStream) -> String {
+        let args = syn::parse2::<ImplForDevicesArgs>(args).unwrap();
+        impl_for_devices_expansion(args, syn::parse2(item).unwrap()).to_string()
+    }
+
+    fn compile_error(message: &str) -> String {
+        syn::Error::new(Span::call_site(), message)
+            .to_compile_error()
+            .to_string()
+    }
+
+    #[test]
+    fn replaces_cpu_in_types_and_qualified_expressions() {
+        let expanded = impl_stack(
+            quote!(),
+            quote! {
+                impl<T: Copy> Read<T, CPU> for CPU {
+                    fn read(&self, buf: &Buffer<T, custos::CPU>) -> Vec<T> {
+                        let device = CPU::new();
+                        let cpu_buffer: CPUBuffer = CPUBuffer::default();
+                        let threads = MAX_CPU_THREADS;
+                        let value = CPU;
+                        println!("CPU");
+                        <CPU as Device>::name()
+                    }
+                }
+            },
+        );
+
+        let expected = quote! {
+            #[cfg(feature = "cpu")]
+            impl<T: Copy> Read<T, CPU> for CPU {
+                fn read(&self, buf: &Buffer<T, custos::CPU>) -> Vec<T> {
+                    let device = CPU::new();
+                    let cpu_buffer: CPUBuffer = CPUBuffer::default();
+                    let threads = MAX_CPU_THREADS;
+                    let value = CPU;
+                    println!("CPU");
+                    <CPU as Device>::name()
+                }
+            }
+
+            #[cfg(feature = "stack")]
+            impl<T: Copy> Read<T, Stack> for Stack {
+                fn read(&self, buf: &Buffer<T, custos::Stack>) -> Vec<T> {
+                    let device = Stack::new();
+                    let cpu_buffer: CPUBuffer = CPUBuffer::default();
+                    let threads = MAX_CPU_THREADS;
+                    let value = CPU;
+                    println!("CPU");
+                    <Stack as Device>::name()
+                }
+            }
+        };
+        assert_eq!(expanded, expected.to_string());
+    }
+
+    #[test]
+    fn cfg_options() {
+        let item = quote!(impl Foo for CPU {});
+
+        let expanded = impl_stack(
+            quote!(cpu_feature = "host", stack_feature = "no-alloc"),
+            item.clone(),
+        );
+        assert_eq!(
+            expanded,
+            quote! {
+                #[cfg(feature = "host")]
+                impl Foo for CPU {}
+                #[cfg(feature = "no-alloc")]
+                impl Foo for Stack {}
+            }
+            .to_string()
+        );
+
+        let expanded = impl_stack(
+            quote!(
+                cpu_cfg = any(feature = "cpu", test),
+                stack_cfg = not(target_os = "none")
+            ),
+            item.clone(),
+        );
+        assert_eq!(
+            expanded,
+            quote! {
+                #[cfg(any(feature = "cpu", test))]
+                impl Foo for CPU {}
+                #[cfg(not(target_os = "none"))]
+                impl Foo for Stack {}
+            }
+            .to_string()
+        );
+
+        let expanded = impl_stack(quote!(no_cfg), item.clone());
+        assert_eq!(
+            expanded,
+            quote! {
+                impl Foo for CPU {}
+                impl Foo for Stack {}
+            }
+            .to_string()
+        );
+
+        assert_eq!(
+            impl_stack_error(quote!(no_cfg, cpu_feature = "host"), item.clone()),
+            "`no_cfg` cannot be combined with other conditions"
+        );
+        assert_eq!(
+            impl_stack_error(quote!(cpu_feature = "a", cpu_cfg = test), item.clone()),
+            "the condition of this impl block is already set"
+        );
+        assert!(impl_stack_error(quote!(gpu_feature = "a"), item).starts_with("unknown option"));
+    }
+
+    #[test]
+    fn const_n_threads_through_buffers() {
+        let expanded = impl_stack(
+            quote!(const_n),
+            quote! {
+                impl<T: Number, S: Shape> Max<T, CPU, S> for CPU
+                where
+                    Buffer<T, CPU, S>: Clone,
+                {
+                    type Output = Buffer<T, CPU, S>;
+
+                    fn max(&self, buf: &Buffer<T, CPU, S>) -> Option<(Buffer<T, CPU, S>, usize)> {
+                        let buf: Buffer<T, CPU, S> = buf.clone();
+                        let out = Buffer::<T, custos::CPU, S>::new(self, 1);
+                        let host: Buffer<T, OpenCL> = self.host();
+                        None
+                    }
+                }
+            },
+        );
+
+        let expected = quote! {
+            #[cfg(feature = "cpu")]
+            impl<T: Number, S: Shape> Max<T, CPU, S> for CPU
+            where
+                Buffer<T, CPU, S>: Clone,
+            {
+                type Output = Buffer<T, CPU, S>;
+
+                fn max(&self, buf: &Buffer<T, CPU, S>) -> Option<(Buffer<T, CPU, S>, usize)> {
+                    let buf: Buffer<T, CPU, S> = buf.clone();
+                    let out = Buffer::<T, custos::CPU, S>::new(self, 1);
+                    let host: Buffer<T, OpenCL> = self.host();
+                    None
+                }
+            }
+
+            #[cfg(feature = "stack")]
+            impl<T: Number, S: Shape, const N: usize> Max<T, Stack, S, N> for Stack
+            where
+                Buffer<T, Stack, S, N>: Clone,
+                Stack: custos::Alloc<T, N>
+            {
+                type Output = Buffer<T, Stack, S, N>;
+
+                fn max(&self, buf: &Buffer<T, Stack, S, N>) -> Option<(Buffer<T, Stack, S, N>, usize)> {
+                    let buf: Buffer<T, Stack, S, N> = buf.clone();
+                    let out = Buffer::<T, custos::Stack, S, N>::new(self, 1);
+                    let host: Buffer<T, OpenCL> = self.host();
+                    None
+                }
+            }
+        };
+        assert_eq!(expanded, expected.to_string());
+    }
+
+    #[test]
+    fn const_n_errors() {
+        assert!(impl_stack_error(quote!(const_n), quote!(impl CPU {}))
+            .contains("`const_n` needs the datatype"));
+        assert!(impl_stack_error(
+            quote!(const_n),
+            quote!(
+                impl<T> CPU {}
+            )
+        )
+        .contains("needs a trait impl"));
+        assert!(impl_stack_error(
+            quote!(const_n),
+            quote!(
+                impl<T, const N: usize> Foo<T> for CPU {}
+            )
+        )
+        .contains("already declared"));
+    }
+
+    #[test]
+    fn skip_body_and_only() {
+        let expanded = impl_stack(
+            quote!(),
+            quote! {
+                impl<T> Foo<T> for CPU {
+                    #[stack(skip)]
+                    fn uses_default(&self) {}
+
+                    #[stack(body = { stack_version() })]
+                    fn replaced(&self) -> usize {
+                        cpu_version()
+                    }
+
+                    #[stack(body = 0)]
+                    fn expr_body(&self) -> usize {
+                        1
+                    }
+
+                    #[cpu_only]
+                    fn twice(&self) -> &'static str {
+                        "cpu"
+                    }
+
+                    #[stack_only]
+                    fn twice(&self) -> &'static str {
+                        "stack"
+                    }
+
+                    #[inline]
+                    fn kept(&self) {}
+                }
+            },
+        );
+
+        let expected = quote! {
+            #[cfg(feature = "cpu")]
+            impl<T> Foo<T> for CPU {
+                fn uses_default(&self) {}
+
+                fn replaced(&self) -> usize {
+                    cpu_version()
+                }
+
+                fn expr_body(&self) -> usize {
+                    1
+                }
+
+                fn twice(&self) -> &'static str {
+                    "cpu"
+                }
+
+                #[inline]
+                fn kept(&self) {}
+            }
+
+            #[cfg(feature = "stack")]
+            impl<T> Foo<T> for Stack {
+                fn replaced(&self) -> usize {
+                    stack_version()
+                }
+
+                fn expr_body(&self) -> usize {
+                    0
+                }
+
+                fn twice(&self) -> &'static str {
+                    "stack"
+                }
+
+                #[inline]
+                fn kept(&self) {}
+            }
+        };
+        assert_eq!(expanded, expected.to_string());
+    }
+
+    #[test]
+    fn where_bounds_of_the_stack_copy() {
+        let expanded = impl_stack(
+            quote!(),
+            quote! {
+                #[stack(where T: Copy)]
+                impl<T> Buffers<T> for CPU {
+                    fn zeros<U>(&self) -> U where U: From<T> {
+                        todo!()
+                    }
+                }
+            },
+        );
+
+        let expected = quote! {
+            #[cfg(feature = "cpu")]
+            impl<T> Buffers<T> for CPU {
+                fn zeros<U>(&self) -> U where U: From<T> {
+                    todo!()
+                }
+            }
+
+            #[cfg(feature = "stack")]
+            impl<T> Buffers<T> for Stack where T: Copy {
+                fn zeros<U>(&self) -> U where U: From<T> {
+                    todo!()
+                }
+            }
+        };
+        assert_eq!(expanded, expected.to_string());
+
+        // methods of inherent impls can have their own bounds
+        let expanded = impl_stack(
+            quote!(),
+            quote! {
+                impl<T> Wrapper<T, CPU> {
+                    #[stack(where T: Default)]
+                    fn zeros<U>(&self) -> U where U: From<T> {
+                        todo!()
+                    }
+                }
+            },
+        );
+
+        let expected = quote! {
+            #[cfg(feature = "cpu")]
+            impl<T> Wrapper<T, CPU> {
+                fn zeros<U>(&self) -> U where U: From<T> {
+                    todo!()
+                }
+            }
+
+            #[cfg(feature = "stack")]
+            impl<T> Wrapper<T, Stack> {
+                fn zeros<U>(&self) -> U where U: From<T>, T: Default {
+                    todo!()
+                }
+            }
+        };
+        assert_eq!(expanded, expected.to_string());
+    }
+
+    #[test]
+    fn invalid_item_attributes() {
+        let error = |item| impl_stack_error(quote!(), item);
+
+        assert_eq!(
+            error(quote!(impl Foo for CPU {
+                #[cpu_only]
+                #[stack_only]
+                fn f() {}
+            })),
+            compile_error("an item cannot be both `#[cpu_only]` and `#[stack_only]`")
+        );
+        assert_eq!(
+            error(quote!(impl Foo for CPU {
+                #[cpu_only]
+                #[stack(skip)]
+                fn f() {}
+            })),
+            compile_error(
+                "`#[stack(...)]` cannot be combined with `#[cpu_only]` or `#[stack_only]`"
+            )
+        );
+        assert_eq!(
+            error(quote!(impl Foo for CPU {
+                #[stack(skip, body = {})]
+                fn f() {}
+            })),
+            compile_error("`skip` cannot be combined with `body`")
+        );
+        assert_eq!(
+            error(quote!(impl Foo for CPU {
+                #[stack(body = 1)]
+                const N: usize = 0;
+            })),
+            compile_error(
+                "`#[stack(body = ...)]` and `#[stack(where ...)]` can only be used on methods"
+            )
+        );
+        assert_eq!(
+            error(quote!(
+                #[stack(skip)]
+                impl Foo for CPU {}
+            )),
+            compile_error("only `#[stack(where ...)]` can be used on the impl block")
+        );
+        assert_eq!(
+            error(quote!(impl<T> Foo<T> for CPU {
+                #[stack(where T: Copy)]
+                fn f() {}
+            })),
+            compile_error(
+                "`#[stack(where ...)]` cannot be used on a method of a trait impl, \
+                its bounds would be stricter than the trait's; use `#[stack(where ...)]` on the impl block instead"
+            )
+        );
+    }
+
+    #[test]
+    fn impl_for_devices_copies() {
+        let expanded = impl_for_devices(
+            quote!(
+                Stack,
+                custos::OpenCL => "cl",
+                MyCpuWrapper<CPU> => cfg(all(feature = "cpu", test))
+            ),
+            quote!(impl Foo for CPU {
+                fn f() -> Buffer<f32, CPU> {
+                    CPU::new().buffer()
+                }
+            }),
+        );
+
+        let expected = quote! {
+            #[cfg(feature = "cpu")]
+            impl Foo for CPU {
+                fn f() -> Buffer<f32, CPU> {
+                    CPU::new().buffer()
+                }
+            }
+
+            #[cfg(feature = "stack")]
+            impl Foo for Stack {
+                fn f() -> Buffer<f32, Stack> {
+                    Stack::new().buffer()
+                }
+            }
+
+            #[cfg(feature = "cl")]
+            impl Foo for custos::OpenCL {
+                fn f() -> Buffer<f32, custos::OpenCL> {
+                    custos::OpenCL::new().buffer()
+                }
+            }
+
+            #[cfg(all(feature = "cpu", test))]
+            impl Foo for MyCpuWrapper<CPU> {
+                fn f() -> Buffer<f32, MyCpuWrapper<CPU> > {
+                    MyCpuWrapper::<CPU>::new().buffer()
+                }
+            }
+        };
+        assert_eq!(expanded, expected.to_string());
+    }
+
+    #[test]
+    fn impl_for_devices_cfg_options() {
+        let item = quote!(impl Foo for CPU {});
+
+        assert_eq!(
+            impl_for_devices(quote!(cpu_feature = "host", Stack), item.clone()),
+            quote! {
+                #[cfg(feature = "host")]
+                impl Foo for CPU {}
+
+                #[cfg(feature = "stack")]
+                impl Foo for Stack {}
+            }
+            .to_string()
+        );
+        assert_eq!(
+            impl_for_devices(
+                quote!(Stack, cpu_cfg = all(feature = "cpu", not(miri))),
+                item.clone()
+            ),
+            quote! {
+                #[cfg(all(feature = "cpu", not(miri)))]
+                impl Foo for CPU {}
+
+                #[cfg(feature = "stack")]
+                impl Foo for Stack {}
+            }
+            .to_string()
+        );
+        assert_eq!(
+            impl_for_devices(quote!(no_cfg, Stack, MyCpuWrapper), item),
+            quote! {
+                impl Foo for CPU {}
+
+                impl Foo for Stack {}
+
+                impl Foo for MyCpuWrapper {}
+            }
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn impl_for_devices_errors() {
+        let error = |args| match syn::parse2::<ImplForDevicesArgs>(args) {
+            Ok(_) => panic!("invalid arguments were accepted"),
+            Err(err) => err.to_string(),
+        };
+
+        assert_eq!(
+            error(quote!(custos::OpenCL, OpenCL)),
+            "the feature `open_cl` is used for two devices"
+        );
+        assert_eq!(
+            error(quote!(no_cfg, Stack => "stack")),
+            "`no_cfg` cannot be combined with other conditions"
+        );
+        assert_eq!(
+            error(quote!(cpu_feature = "a", cpu_feature = "b", Stack)),
+            "the condition of the `CPU` impl block is already set"
+        );
+        assert_eq!(
+            error(quote!(stack_feature = "stack", Stack)),
+            "unknown option, expected one of `cpu_feature`, `cpu_cfg`, `no_cfg` or a device"
+        );
+        assert_eq!(
+            error(quote!(cpu_feature = "host")),
+            "expected a list of devices, e.g. `Stack, custos::OpenCL => \"opencl\"`"
+        );
+    }
+
+    #[test]
+    fn device_features_are_snake_case() {
+        assert_eq!(snake_case("Stack"), "stack");
+        assert_eq!(snake_case("OpenCL"), "open_cl");
+        assert_eq!(snake_case("MyCpuWrapper"), "my_cpu_wrapper");
+        assert_eq!(snake_case("CUDA"), "cuda");
+        assert_eq!(snake_case("Wgpu2Device"), "wgpu2_device");
+    }
+}
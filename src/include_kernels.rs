@@ -0,0 +1,392 @@
+use std::path::Path;
+
+use custos_macro_build::{compiler::CudaCompiler, find_kernels, opencl::validate_opencl};
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    Ident, LitStr, Token,
+};
+
+use crate::{
+    cuda::compile_kernel,
+    kernel_src::KernelSrc,
+    options::{
+        parse_cuda_option, resolve_path, track_env_vars, CudaMode, CudaOptions, CUDA_ENV_VARS,
+    },
+};
+
+/// The input of `include_kernels!`: a directory or `key = value` pairs.
+struct IncludeKernelsInput {
+    dir: LitStr,
+    module: Option<Ident>,
+    options: CudaOptions,
+}
+
+impl Parse for IncludeKernelsInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = CudaOptions::from_env();
+
+        if input.peek(LitStr) {
+            return Ok(IncludeKernelsInput {
+                dir: input.parse()?,
+                module: None,
+                options,
+            });
+        }
+
+        let mut dir = None;
+        let mut module = None;
+
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+
+            match key.to_string().as_str() {
+                "path" => dir = Some(input.parse()?),
+                "module" => module = Some(input.parse()?),
+                _ if parse_cuda_option(&mut options, &key, input)? => {}
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "unknown option, expected one of `path`, `module`, `arch`, `opt_level`, `defines`, `include_dirs`, `flags`",
+                    ))
+                }
+            }
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+
+        let dir = dir.ok_or_else(|| {
+            syn::Error::new(
+                Span::call_site(),
+                "missing kernel directory, e.g. `path = \"kernels/\"`",
+            )
+        })?;
+
+        Ok(IncludeKernelsInput {
+            dir,
+            module,
+            options,
+        })
+    }
+}
+
+/// A kernel file found in the directory.
+struct KernelFile {
+    /// The path relative to the directory, with `/` as separator.
+    name: String,
+    ident: Ident,
+    kernel: KernelSrc,
+    /// The options of the macro with the directory of the file as first include dir, like `cuda!` adds it.
+    options: CudaOptions,
+}
+
+pub fn include_kernels_expansion(input: TokenStream) -> TokenStream {
+    let IncludeKernelsInput {
+        dir,
+        module,
+        options,
+    } = match syn::parse2(input) {
+        Ok(input) => input,
+        Err(err) => return err.to_compile_error(),
+    };
+
+    match expand(&dir, module, &options) {
+        Ok(expanded) => expanded,
+        Err(err) => err.to_compile_error(),
+    }
+}
+
+fn expand(dir: &LitStr, module: Option<Ident>, options: &CudaOptions) -> syn::Result<TokenStream> {
+    let root = resolve_path(dir.value());
+    let paths = find_kernels(&root, &["cu", "cl"]).map_err(|err| {
+        syn::Error::new(
+            dir.span(),
+            format!(
+                "Could not read the kernel directory {}: {err}",
+                root.display()
+            ),
+        )
+    })?;
+
+    let module = match module {
+        Some(module) => module,
+        None => module_ident(&root, dir.span())?,
+    };
+
+    let mut files: Vec<KernelFile> = Vec::new();
+    for path in paths {
+        let relative = path.strip_prefix(&root).unwrap_or(&path);
+        let name = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let ident = const_ident(relative);
+
+        if let Some(other) = files.iter().find(|file| file.ident == ident) {
+            return Err(syn::Error::new(
+                dir.span(),
+                format!(
+                    "`{name}` and `{}` would both be named `{ident}`",
+                    other.name
+                ),
+            ));
+        }
+
+        let mut kernel = KernelSrc::read(path, dir.span())?;
+        let mut options = options.clone();
+        if let Some(dir) = kernel.path().and_then(Path::parent) {
+            options.include_dirs.insert(0, dir.to_path_buf());
+        }
+        kernel.resolve_includes(&options.include_dirs);
+
+        files.push(KernelFile {
+            name,
+            ident,
+            kernel,
+            options,
+        });
+    }
+
+    let mode = CudaMode::from_env().map_err(|message| syn::Error::new(dir.span(), message))?;
+    let compiler = CudaCompiler::from_env();
+
+    let mut diagnostics = TokenStream::new();
+    let mut consts = TokenStream::new();
+    let mut kernels = Vec::new();
+    let mut sources = Vec::new();
+
+    for KernelFile {
+        name,
+        ident,
+        kernel,
+        options,
+    } in &files
+    {
+        let path = kernel.path().unwrap_or(Path::new("")).display().to_string();
+        diagnostics.extend(kernel.track_files());
+
+        let (value, doc) =
+            if name.ends_with(".cu") && mode == CudaMode::Ptx {
+                let (file_diagnostics, ptx) = compile_kernel(
+                    kernel,
+                    &kernel.src,
+                    options,
+                    &compiler,
+                    &format!("{} ({name})", compiler.name()),
+                );
+                diagnostics.extend(file_diagnostics);
+
+                let ptx = ptx.unwrap_or_default();
+                (quote!(#ptx), format!("`{name}` compiled to PTX."))
+            } else {
+                if name.ends_with(".cl") {
+                    diagnostics.extend(kernel.emit_diagnostics(
+                        &format!("opencl ({name})"),
+                        &validate_opencl(&kernel.src),
+                    ));
+                }
+                (
+                    quote!(include_str!(#path)),
+                    format!("The source of `{name}`."),
+                )
+            };
+
+        consts.extend(quote! {
+            #[doc = #doc]
+            pub const #ident: &str = #value;
+        });
+        kernels.push(quote!((#name, #ident)));
+        sources.push(quote!((#name, include_str!(#path))));
+    }
+
+    let track_env = track_env_vars(CUDA_ENV_VARS);
+
+    Ok(quote! {
+        #[allow(dead_code)]
+        pub mod #module {
+            #track_env
+
+            const _: () = {
+                #diagnostics
+            };
+
+            #consts
+
+            /// The file name of every kernel and its PTX (`.cu`) or its source (`.cl`, and `.cu` in runtime mode).
+            pub const KERNELS: &[(&str, &str)] = &[#(#kernels),*];
+
+            /// The file name and the original source of every kernel.
+            pub const SOURCES: &[(&str, &str)] = &[#(#sources),*];
+
+            /// Returns the PTX or source of a kernel by its file name, e.g. `"nn/relu.cu"`.
+            pub fn kernel(name: &str) -> Option<&'static str> {
+                KERNELS.iter().find(|(file, _)| *file == name).map(|(_, kernel)| *kernel)
+            }
+
+            /// Returns the original source of a kernel by its file name.
+            pub fn source(name: &str) -> Option<&'static str> {
+                SOURCES.iter().find(|(file, _)| *file == name).map(|(_, src)| *src)
+            }
+        }
+    })
+}
+
+/// `nn/relu.cu` -> `NN_RELU_CU`, the extension is kept so `add.cu` and `add.cl` can live side by side
+fn const_ident(relative: &Path) -> Ident {
+    let name = relative
+        .to_string_lossy()
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect::<String>();
+
+    match name.starts_with(|c: char| c.is_ascii_digit()) {
+        true => format_ident!("_{name}"),
+        false => format_ident!("{name}"),
+    }
+}
+
+/// The module is named after the directory.
+fn module_ident(dir: &Path, span: Span) -> syn::Result<Ident> {
+    let name = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().replace(['-', '.', ' '], "_"))
+        .unwrap_or_default();
+
+    syn::parse_str::<Ident>(&name).map_err(|_| {
+        syn::Error::new(
+            span,
+            format!("`{name}` is not a valid module name, set one with `module = name`"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use proc_macro2::Span;
+    use quote::quote;
+    use syn::LitStr;
+
+    use super::{const_ident, expand, module_ident};
+    use crate::options::CudaOptions;
+
+    /// A fresh kernel directory named `name` with the given files.
+    fn kernel_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("custos-include-kernels-{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, src) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, src).unwrap();
+        }
+        dir
+    }
+
+    fn expand_dir(dir: &Path) -> syn::Result<String> {
+        let dir = LitStr::new(&dir.display().to_string(), Span::call_site());
+        expand(&dir, None, &CudaOptions::default()).map(|tokens| tokens.to_string())
+    }
+
+    #[test]
+    fn const_idents() {
+        assert_eq!(const_ident(Path::new("nn/relu.cu")), "NN_RELU_CU");
+        assert_eq!(const_ident(Path::new("add-2.cl")), "ADD_2_CL");
+        assert_eq!(const_ident(Path::new("2d/conv.cu")), "_2D_CONV_CU");
+    }
+
+    #[test]
+    fn module_idents() {
+        let span = Span::call_site();
+        assert_eq!(
+            module_ident(Path::new("src/kernels"), span).unwrap(),
+            "kernels"
+        );
+        assert_eq!(
+            module_ident(Path::new("my-kernels.v2 x"), span).unwrap(),
+            "my_kernels_v2_x"
+        );
+
+        let err = module_ident(Path::new("2d"), span).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`2d` is not a valid module name, set one with `module = name`"
+        );
+    }
+
+    #[test]
+    fn kernels_and_sources_of_every_file() {
+        let dir = kernel_dir(
+            "cl-kernels",
+            &[
+                (
+                    "add.cl",
+                    "__kernel void add(__global float* x) { x[0] += 1.0f; }",
+                ),
+                (
+                    "nn/relu.cl",
+                    "__kernel void relu(__global float* x) { x[0] = max(x[0], 0.0f); }",
+                ),
+            ],
+        );
+        let tokens = expand_dir(&dir).unwrap();
+
+        let add = dir.join("add.cl").display().to_string();
+        let relu = dir.join("nn/relu.cl").display().to_string();
+        let add_source = quote!(("add.cl", include_str!(#add)));
+        let relu_source = quote!(("nn/relu.cl", include_str!(#relu)));
+        let expected = [
+            quote!(pub mod cl_kernels).to_string(),
+            quote!(
+                pub const ADD_CL: &str = include_str!(#add);
+            )
+            .to_string(),
+            quote!(
+                pub const NN_RELU_CL: &str = include_str!(#relu);
+            )
+            .to_string(),
+            quote!(
+                pub const KERNELS: &[(&str, &str)] =
+                    &[("add.cl", ADD_CL), ("nn/relu.cl", NN_RELU_CL)];
+            )
+            .to_string(),
+            quote!(
+                pub const SOURCES: &[(&str, &str)] = &[#add_source, #relu_source];
+            )
+            .to_string(),
+        ];
+        for expected in expected {
+            assert!(tokens.contains(&expected), "{expected}\nin\n{tokens}");
+        }
+        assert!(!tokens.contains("compile_error"), "{tokens}");
+    }
+
+    #[test]
+    fn files_with_the_same_const_name() {
+        let dir = kernel_dir(
+            "same-names",
+            &[
+                ("a-b.cl", "__kernel void a(__global float* x) {}"),
+                ("a_b.cl", "__kernel void b(__global float* x) {}"),
+            ],
+        );
+        let err = expand_dir(&dir).unwrap_err().to_string();
+
+        assert!(
+            err == "`a_b.cl` and `a-b.cl` would both be named `A_B_CL`"
+                || err == "`a-b.cl` and `a_b.cl` would both be named `A_B_CL`",
+            "{err}"
+        );
+    }
+}
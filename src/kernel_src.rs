@@ -0,0 +1,467 @@
+use std::path::{Path, PathBuf};
+
+use custos_macro_build::{
+    diagnostics::{Diagnostic, Severity},
+    includes::resolve_includes,
+};
+use proc_macro2::{Delimiter, LineColumn, Span, TokenStream, TokenTree};
+use quote::{quote, quote_spanned};
+use syn::LitStr;
+
+use crate::options::resolve_path;
+
+/// Kernel source code and where it was written in the Rust source.
+pub struct KernelSrc {
+    pub src: String,
+    /// Local headers included by the source.
+    pub includes: Vec<PathBuf>,
+    origin: Origin,
+}
+
+enum Origin {
+    Literal(LitStr),
+    /// Source written as tokens. Kernel line `n` is line `first_line + n - 1` of `file`,
+    /// kernel columns are Rust columns (1-based).
+    Tokens {
+        span: Span,
+        file: String,
+        first_line: usize,
+        /// The tokens with their start and end, to find the span of a kernel location.
+        tokens: Vec<(LineColumn, LineColumn, Span)>,
+    },
+    /// Source without locations, e.g. stringified tokens.
+    Text(Span),
+    /// A kernel file, `span` is the span of the path literal.
+    File {
+        path: PathBuf,
+        span: Span,
+    },
+}
+
+impl KernelSrc {
+    pub fn from_lit(lit: LitStr) -> KernelSrc {
+        KernelSrc {
+            src: lit.value(),
+            includes: Vec::new(),
+            origin: Origin::Literal(lit),
+        }
+    }
+
+    /// Rebuilds kernel source code that is written as tokens, e.g. `cuda!(extern "C" __global__ void ...)`.
+    ///
+    /// `enclosing` is the span of the delimited tokens including the delimiters, e.g. the macro invocation.
+    /// The text is cut out of the Rust source file, so comments, preprocessor lines and the layout are kept.
+    /// If the file is not available, the text is rebuilt from the token positions, which keeps the layout but drops comments.
+    /// Either way, the kernel lines are the lines of the Rust file, starting at the line of the opening delimiter.
+    pub fn from_tokens(tokens: TokenStream, enclosing: Span) -> KernelSrc {
+        let mut leaves = Vec::new();
+        flatten(tokens.clone(), &mut leaves);
+
+        let (Some((first, first_text)), Some((_, last_text))) = (leaves.first(), leaves.last())
+        else {
+            return KernelSrc::from_text(tokens.to_string(), enclosing);
+        };
+        // no span locations, e.g. outside of a proc macro invocation
+        if first.start().line == 0 {
+            return KernelSrc::from_text(tokens.to_string(), enclosing);
+        }
+
+        let file = first
+            .local_file()
+            .and_then(|path| std::fs::read_to_string(path).ok());
+        let sliced = file.as_deref().and_then(|file| {
+            let (start, text) = slice_delimited(file, enclosing.start(), enclosing.end())?;
+            // the enclosing span may belong to another file, e.g. inside of `macro_rules!`
+            let first = text.find(first_text.as_str())?;
+            let last = text.rfind(last_text.as_str())?;
+            (first <= last).then_some((start, text))
+        });
+        let (start, text) = sliced.unwrap_or_else(|| (first.start(), rebuild(&leaves)));
+
+        KernelSrc {
+            // pad the first line, the columns then match the Rust file
+            src: format!("{}{text}\n", " ".repeat(start.column)),
+            includes: Vec::new(),
+            origin: Origin::Tokens {
+                span: enclosing,
+                file: first.file(),
+                first_line: start.line,
+                tokens: leaves
+                    .iter()
+                    .map(|(span, _)| (span.start(), span.end(), *span))
+                    .collect(),
+            },
+        }
+    }
+
+    /// Kernel source without a location in the Rust source.
+    pub fn from_text(src: String, span: Span) -> KernelSrc {
+        KernelSrc {
+            src,
+            includes: Vec::new(),
+            origin: Origin::Text(span),
+        }
+    }
+
+    /// Reads a kernel file, the path is relative to the crate root.
+    pub fn from_file(path: &LitStr) -> syn::Result<KernelSrc> {
+        KernelSrc::read(resolve_path(path.value()), path.span())
+    }
+
+    /// Reads a kernel file, diagnostics are reported at `span`.
+    pub fn read(path: PathBuf, span: Span) -> syn::Result<KernelSrc> {
+        let src = std::fs::read_to_string(&path).map_err(|err| {
+            syn::Error::new(span, format!("Could not read {}: {err}", path.display()))
+        })?;
+
+        Ok(KernelSrc {
+            src,
+            includes: Vec::new(),
+            origin: Origin::File { path, span },
+        })
+    }
+
+    pub fn span(&self) -> Span {
+        match &self.origin {
+            Origin::Literal(lit) => lit.span(),
+            Origin::Tokens { span, .. } | Origin::Text(span) | Origin::File { span, .. } => *span,
+        }
+    }
+
+    /// The path of the kernel file, if the source was read from one.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.origin {
+            Origin::File { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Resolves the local headers of the source.
+    pub fn resolve_includes(&mut self, include_dirs: &[PathBuf]) {
+        let base_dir = self.path().and_then(Path::parent);
+        self.includes = resolve_includes(&self.src, base_dir, include_dirs);
+    }
+
+    /// Makes the kernel file and all included headers a dependency of the calling crate,
+    /// so that cargo rebuilds it if one of them changes.
+    pub fn track_files(&self) -> TokenStream {
+        self.path()
+            .into_iter()
+            .chain(self.includes.iter().map(PathBuf::as_path))
+            .map(|path| {
+                let path = path.display().to_string();
+                quote!(
+                    const _: &[u8] = include_bytes!(#path);
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the span of the given 1-based kernel line and column.
+    /// Falls back to the span of the whole literal if the compiler does not support sub-spans.
+    pub fn span_at(&self, line: usize, column: usize) -> Span {
+        if let Origin::Tokens {
+            first_line, tokens, ..
+        } = &self.origin
+        {
+            let location = LineColumn {
+                line: (first_line + line).saturating_sub(1),
+                column: column.saturating_sub(1),
+            };
+            // the token at the location, otherwise the closest one on the same line,
+            // otherwise a token spanning several lines
+            let on_line = || {
+                tokens
+                    .iter()
+                    .filter(|(start, _, _)| start.line == location.line)
+            };
+            return on_line()
+                .rev()
+                .find(|(start, _, _)| *start <= location)
+                .or_else(|| on_line().next())
+                .or_else(|| {
+                    tokens
+                        .iter()
+                        .find(|(start, end, _)| *start <= location && location < *end)
+                })
+                .map_or_else(|| self.span(), |(_, _, span)| *span);
+        }
+        let Origin::Literal(lit) = &self.origin else {
+            return self.span();
+        };
+        let Some(offset) = self.token_offset(line, column) else {
+            return self.span();
+        };
+        let token = lit.token();
+        let end = token.to_string()[offset..]
+            .chars()
+            .next()
+            .map_or(offset, |c| offset + c.len_utf8());
+
+        token.subspan(offset..end).unwrap_or_else(|| lit.span())
+    }
+
+    /// Returns the source location (`file:line:column`) of the given kernel line and column.
+    pub fn location(&self, line: usize, column: usize) -> Option<String> {
+        let (file, line, column) = self.position(line, column)?;
+        Some(format!("{file}:{line}:{column}"))
+    }
+
+    /// Returns the file, line and column of the given kernel line and column in the Rust source or the kernel file.
+    pub fn position(&self, line: usize, column: usize) -> Option<(String, usize, usize)> {
+        let lit = match &self.origin {
+            Origin::Literal(lit) => lit,
+            Origin::File { path, .. } => return Some((path.display().to_string(), line, column)),
+            Origin::Tokens {
+                file, first_line, ..
+            } => return Some((file.clone(), (first_line + line).saturating_sub(1), column)),
+            Origin::Text(_) => return None,
+        };
+        let offset = self.token_offset(line, column)?;
+        let token = lit.token().to_string();
+
+        let start = lit.span().start();
+        if start.line == 0 {
+            return None;
+        }
+
+        let before = &token[..offset];
+        let (line, column) = match before.rfind('\n') {
+            Some(newline) => (
+                start.line + before.matches('\n').count(),
+                before[newline + 1..].chars().count() + 1,
+            ),
+            None => (start.line, start.column + before.chars().count() + 1),
+        };
+
+        Some((lit.span().file(), line, column))
+    }
+
+    /// Byte offset in the literal token (including quotes and prefix) of a kernel line and column.
+    fn token_offset(&self, line: usize, column: usize) -> Option<usize> {
+        let Origin::Literal(lit) = &self.origin else {
+            return None;
+        };
+        let line_start = self
+            .src
+            .split_inclusive('\n')
+            .take(line.checked_sub(1)?)
+            .map(str::len)
+            .sum::<usize>();
+
+        let rest = self.src.get(line_start..)?;
+        let line_len = rest.find('\n').unwrap_or(rest.len());
+        let in_line = rest[..line_len]
+            .char_indices()
+            .nth(column.saturating_sub(1))
+            .map_or(line_len, |(idx, _)| idx);
+
+        value_to_token_offset(&lit.token().to_string(), line_start + in_line)
+    }
+
+    /// Converts the compiler diagnostics into `compile_error!`s and warnings, spanned to their location in the kernel source.
+    pub fn emit_diagnostics(&self, compiler: &str, diagnostics: &[Diagnostic]) -> TokenStream {
+        diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity != Severity::Note)
+            .map(|diagnostic| {
+                let (span, message) = self.describe(compiler, diagnostic);
+                match diagnostic.severity {
+                    Severity::Error => quote_spanned!(span=> compile_error!(#message);),
+                    _ => emit_warning(&message, span),
+                }
+            })
+            .collect()
+    }
+
+    fn describe(&self, compiler: &str, diagnostic: &Diagnostic) -> (Span, String) {
+        let severity = match diagnostic.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        };
+        let mut message = format!("{compiler} {severity}: {}", diagnostic.message);
+
+        let span = match (&diagnostic.file, diagnostic.line) {
+            (Some(file), Some(line)) => {
+                message.push_str(&format!("\n --> {file}:{line}"));
+                self.span()
+            }
+            (None, Some(line)) => {
+                let column = diagnostic.column.unwrap_or(1);
+                message.push_str(&format!("\n --> kernel line {line}, column {column}"));
+                if let Some(location) = self.location(line, column) {
+                    message.push_str(&format!(" ({location})"));
+                }
+                self.span_at(line, column)
+            }
+            _ => self.span(),
+        };
+
+        for context in &diagnostic.context {
+            message.push('\n');
+            message.push_str(context);
+        }
+
+        (span, message)
+    }
+}
+
+/// Stable proc macros cannot emit warnings directly. Using a deprecated item reports the message as a warning at `span`.
+pub fn emit_warning(message: &str, span: Span) -> TokenStream {
+    let ident = syn::Ident::new("kernel_compiler_warning", span);
+    let item = quote_spanned! {span=>
+        #[deprecated(note = #message)]
+        #[allow(non_upper_case_globals)]
+        const #ident: () = ();
+    };
+    quote! {
+        {
+            #item
+            #[allow(clippy::let_unit_value)]
+            let _ = #ident;
+        }
+    }
+}
+
+/// Collects the leaf tokens with their text. Delimiters are separate tokens.
+fn flatten(tokens: TokenStream, leaves: &mut Vec<(Span, String)>) {
+    for token in tokens {
+        let TokenTree::Group(group) = token else {
+            leaves.push((token.span(), token.to_string()));
+            continue;
+        };
+        let (open, close) = match group.delimiter() {
+            Delimiter::Parenthesis => ("(", ")"),
+            Delimiter::Brace => ("{", "}"),
+            Delimiter::Bracket => ("[", "]"),
+            Delimiter::None => {
+                flatten(group.stream(), leaves);
+                continue;
+            }
+        };
+        leaves.push((group.span_open(), open.to_string()));
+        flatten(group.stream(), leaves);
+        leaves.push((group.span_close(), close.to_string()));
+    }
+}
+
+/// Cuts the text between the delimiters of a delimited span out of a file,
+/// e.g. the inside of `{ ... }` or of `cuda!( ... )`.
+/// Returns the location right after the opening delimiter, too.
+fn slice_delimited(file: &str, start: LineColumn, end: LineColumn) -> Option<(LineColumn, String)> {
+    let offset = |location: LineColumn| {
+        let line_start = file
+            .split_inclusive('\n')
+            .take(location.line.checked_sub(1)?)
+            .map(str::len)
+            .sum::<usize>();
+        let line = file.get(line_start..)?;
+        let in_line = line
+            .char_indices()
+            .nth(location.column)
+            .map_or(line.len(), |(idx, _)| idx);
+        Some(line_start + in_line)
+    };
+    let text = file.get(offset(start)?..offset(end)?)?;
+
+    let open = text.find(['(', '{', '['])?;
+    let inner = text.get(open + 1..text.len().checked_sub(1)?)?;
+
+    let before = &text[..=open];
+    let start = match before.rfind('\n') {
+        Some(newline) => LineColumn {
+            line: start.line + before.matches('\n').count(),
+            column: before[newline + 1..].chars().count(),
+        },
+        None => LineColumn {
+            line: start.line,
+            column: start.column + before.chars().count(),
+        },
+    };
+    Some((start, inner.to_string()))
+}
+
+/// Rebuilds the text from the token positions.
+fn rebuild(leaves: &[(Span, String)]) -> String {
+    let mut text = String::new();
+    let Some((first, _)) = leaves.first() else {
+        return text;
+    };
+    let mut position = first.start();
+
+    for (span, token) in leaves {
+        let start = span.start();
+        if start.line > position.line {
+            text.push_str(&"\n".repeat(start.line - position.line));
+            position = LineColumn {
+                line: start.line,
+                column: 0,
+            };
+        }
+        text.push_str(&" ".repeat(start.column.saturating_sub(position.column)));
+        if start.column < position.column && !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(token);
+        position = span.end();
+    }
+    text
+}
+
+/// Maps a byte offset in the value of a string literal to the byte offset in its token representation.
+fn value_to_token_offset(token: &str, value_offset: usize) -> Option<usize> {
+    let (prefix_len, raw) = if let Some(hashes) = token.strip_prefix('r') {
+        (
+            2 + hashes.len() - hashes.trim_start_matches('#').len(),
+            true,
+        )
+    } else {
+        (1, false)
+    };
+
+    if raw {
+        return Some(prefix_len + value_offset);
+    }
+
+    let body = token.get(prefix_len..token.len().checked_sub(1)?)?;
+    let mut chars = body.char_indices().peekable();
+    let mut value_pos = 0;
+
+    while let Some((idx, c)) = chars.next() {
+        if value_pos >= value_offset {
+            return Some(prefix_len + idx);
+        }
+        if c != '\\' {
+            value_pos += c.len_utf8();
+            continue;
+        }
+        match chars.next() {
+            Some((_, 'x')) => {
+                chars.next();
+                chars.next();
+                value_pos += 1;
+            }
+            Some((_, 'u')) => {
+                let mut code = String::new();
+                for (_, c) in chars.by_ref() {
+                    match c {
+                        '{' => {}
+                        '}' => break,
+                        c => code.push(c),
+                    }
+                }
+                value_pos += u32::from_str_radix(&code, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .map_or(1, char::len_utf8);
+            }
+            // line continuation, skips the newline and the following whitespace
+            Some((_, '\n')) => while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {},
+            Some(_) => value_pos += 1,
+            None => {}
+        }
+    }
+
+    Some(prefix_len + body.len())
+}
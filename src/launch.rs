@@ -0,0 +1,227 @@
+use custos_macro_build::signatures::{parse_kernel_signatures, KernelSignature, Param};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::Ident;
+
+use crate::{
+    dtype::rust_type_of,
+    kernel_src::{emit_warning, KernelSrc},
+};
+
+/// Names of the wrapper arguments that precede the kernel arguments.
+const RESERVED: &[&str] = &["device", "config"];
+
+/// The `LaunchConfig` struct of a generated module.
+pub fn launch_config_struct() -> TokenStream {
+    quote! {
+        /// Grid and block dimensions of a kernel launch.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct LaunchConfig {
+            pub grid: [u32; 3],
+            pub block: [u32; 3],
+            /// Dynamically allocated shared memory.
+            pub shared_mem_bytes: u32,
+        }
+
+        impl LaunchConfig {
+            /// A one-dimensional launch with enough blocks of `block` threads to cover `len` elements.
+            ///
+            /// # Panics
+            ///
+            /// If `block` is 0 or the grid size does not fit in a `u32`.
+            pub fn for_len(len: usize, block: u32) -> LaunchConfig {
+                assert!(block != 0, "a block needs at least one thread");
+                let blocks = len.div_ceil(block as usize);
+                LaunchConfig {
+                    grid: [u32::try_from(blocks).expect("too many blocks for `len`"), 1, 1],
+                    block: [block, 1, 1],
+                    shared_mem_bytes: 0,
+                }
+            }
+        }
+    }
+}
+
+/// Generates a typed launch function for every `extern "C" __global__` kernel of `src`, e.g.
+///
+/// ```ignore
+/// pub fn add(
+///     device: &custos::CUDA,
+///     config: LaunchConfig,
+///     lhs: &custos::Buffer<'_, f32, custos::CUDA>,
+///     len: i32,
+/// ) -> custos::Result<()>
+/// ```
+///
+/// The functions launch the kernel of `ptx()` with the CUDA driver API. The PTX is JIT compiled and loaded
+/// by the driver on the first launch on a device, `kernel_function` keeps the module and its functions for the thread.
+/// Kernels with parameters that have no Rust counterpart get a warning instead of a function.
+pub fn launch_fns(kernel: &KernelSrc, src: &str) -> TokenStream {
+    let (fns, warnings): (Vec<_>, Vec<_>) = parse_kernel_signatures(src)
+        .iter()
+        .map(|signature| match launch_fn(signature) {
+            Ok(launch_fn) => (launch_fn, quote!()),
+            Err(reason) => {
+                let message = format!("no launch function for `{}`: {reason}", signature.name);
+                (
+                    quote!(),
+                    emit_warning(&message, kernel.span_at(signature.line, 1)),
+                )
+            }
+        })
+        .unzip();
+
+    quote! {
+        const _: () = {
+            #(#warnings)*
+        };
+
+        /// Returns the kernel `name` of `ptx()`, the module is loaded once per device and thread.
+        fn kernel_function(
+            device: &custos::CUDA,
+            name: &'static str,
+        ) -> custos::Result<custos::cuda::api::FnHandle> {
+            type Loaded = (custos::cuda::api::Module, Vec<(&'static str, custos::cuda::api::FnHandle)>);
+            std::thread_local! {
+                // keyed by the address of the device, whose context owns the module
+                static MODULES: std::cell::RefCell<Vec<(usize, Loaded)>> = const { std::cell::RefCell::new(Vec::new()) };
+            }
+
+            let key = device as *const custos::CUDA as usize;
+            MODULES.with(|modules| {
+                let mut modules = modules.borrow_mut();
+                let idx = match modules.iter().position(|(device, _)| *device == key) {
+                    Some(idx) => idx,
+                    None => {
+                        let module = custos::cuda::api::load_module_data(std::ffi::CString::new(ptx().src)?)?;
+                        modules.push((key, (module, Vec::new())));
+                        modules.len() - 1
+                    }
+                };
+                let (module, functions) = &mut modules[idx].1;
+                if let Some((_, function)) = functions.iter().find(|(function, _)| *function == name) {
+                    return Ok(*function);
+                }
+                let function = module.function(name)?;
+                functions.push((name, function));
+                Ok(function)
+            })
+        }
+
+        #(#fns)*
+    }
+}
+
+fn launch_fn(signature: &KernelSignature) -> Result<TokenStream, String> {
+    let name = &signature.name;
+    let ident = launch_fn_ident(name)?;
+    if matches!(name.as_str(), "ptx" | "kernel_function") {
+        return Err(format!("the name is taken by `{name}()`"));
+    }
+
+    let params = launch_params(signature, RESERVED, &quote!(custos::CUDA), rust_type_of)?;
+    let args = params.iter().map(|(ident, ty, _)| quote!(#ident: #ty));
+    let values = params.iter().map(|(_, _, value)| value);
+    let doc = format!("Launches `{}`.", c_signature(signature));
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[allow(clippy::too_many_arguments)]
+        pub fn #ident(
+            device: &custos::CUDA,
+            config: LaunchConfig,
+            #(#args),*
+        ) -> custos::Result<()> {
+            let function = kernel_function(device, #name)?;
+            let params = [#(custos::cuda::AsCudaCvoidPtr::as_cvoid_ptr(#values)),*];
+            custos::cuda::api::culaunch_kernel(
+                &function,
+                config.grid,
+                config.block,
+                config.shared_mem_bytes,
+                device.stream(),
+                &params,
+            )?;
+            Ok(())
+        }
+    })
+}
+
+/// The name of the launch function of a kernel.
+pub fn launch_fn_ident(name: &str) -> Result<Ident, String> {
+    syn::parse_str::<Ident>(name).map_err(|_| format!("`{name}` is not a valid Rust identifier"))
+}
+
+/// The name, Rust type and passed value of every kernel parameter.
+///
+/// Parameters named like one of the `reserved` wrapper arguments or unnamed ones become `argN`.
+/// Buffers are passed by reference already, scalars are borrowed.
+pub fn launch_params(
+    signature: &KernelSignature,
+    reserved: &[&str],
+    device: &TokenStream,
+    rust_type_of: fn(&str) -> Option<&'static str>,
+) -> Result<Vec<(Ident, TokenStream, TokenStream)>, String> {
+    signature
+        .params
+        .iter()
+        .enumerate()
+        .map(|(idx, param)| {
+            let ty = rust_param_type(param, device, rust_type_of)?;
+            let ident = match syn::parse_str::<Ident>(&param.name) {
+                Ok(ident) if !reserved.contains(&param.name.as_str()) => ident,
+                _ => format_ident!("arg{idx}"),
+            };
+            let value = match param.pointers {
+                0 => quote!(&#ident),
+                _ => quote!(#ident),
+            };
+            Ok((ident, ty, value))
+        })
+        .collect()
+}
+
+/// The C signature for docs, e.g. `add(float* lhs, int len)`.
+pub fn c_signature(signature: &KernelSignature) -> String {
+    let params = signature
+        .params
+        .iter()
+        .map(|param| {
+            let pointers = "*".repeat(param.pointers);
+            format!("{}{pointers} {}", param.ty, param.name)
+                .trim()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("{}({params})", signature.name)
+}
+
+/// `float*` -> `&custos::Buffer<'_, f32, #device>`, `int` -> `i32`
+fn rust_param_type(
+    param: &Param,
+    device: &TokenStream,
+    rust_type_of: fn(&str) -> Option<&'static str>,
+) -> Result<TokenStream, String> {
+    let unsupported = || {
+        let pointers = "*".repeat(param.pointers);
+        format!(
+            "parameter `{}` has the unsupported type `{}{pointers}`",
+            param.name, param.ty
+        )
+    };
+    if param.local {
+        return Err(format!(
+            "parameter `{}` is `__local` memory, which cannot be passed as a buffer",
+            param.name
+        ));
+    }
+    let ty = rust_type_of(&param.ty).ok_or_else(unsupported)?;
+    let ty = format_ident!("{ty}");
+
+    match param.pointers {
+        0 => Ok(quote!(#ty)),
+        1 => Ok(quote!(&custos::Buffer<'_, #ty, #device>)),
+        _ => Err(unsupported()),
+    }
+}
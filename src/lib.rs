@@ -1,20 +1,42 @@
 mod add_op;
 mod cuda;
+mod cuda_module;
+mod dtype;
+mod hip;
+mod hot_reload;
 mod impl_nnapi_op;
+mod impl_stack;
 mod impl_using_autograd;
+mod include_kernels;
+mod kernel_src;
+mod launch;
+mod opencl;
+mod options;
+#[cfg(feature = "wgsl")]
+mod shader;
+#[cfg(feature = "spirv")]
+mod spirv;
 mod trait_builds;
-
-use std::{
-    hash::{Hash, Hasher},
-    process::Command,
-};
+#[cfg(feature = "wgsl")]
+mod wgsl;
 
 use add_op::add_op_expansion;
+use cuda::cuda_expansion;
+use hip::hip_expansion;
 use impl_nnapi_op::add_nnapi_op_impl;
+use impl_stack::{
+    impl_for_devices_expansion, impl_stack_expansion, ImplForDevicesArgs, ImplStackArgs,
+};
+use include_kernels::include_kernels_expansion;
+use opencl::opencl_expansion;
 
 use impl_using_autograd::add_maybe_empty_trait;
 use quote::{quote, ToTokens};
-use syn::{parse_macro_input, ExprCall, ItemFn, ItemImpl, ItemTrait, LitStr};
+#[cfg(feature = "spirv")]
+use spirv::spirv_expansion;
+use syn::{parse_macro_input, ExprCall, ItemFn, ItemImpl, ItemTrait};
+#[cfg(feature = "wgsl")]
+use wgsl::wgsl_expansion;
 
 /*struct MyMacroInput {
     src: String
@@ -33,52 +55,353 @@ impl syn::parse::Parse for MyMacroInput {
     }
 }*/
 
+/// Compiles CUDA source code to PTX at compile time using nvcc.
+///
+/// Errors and warnings of nvcc are reported at their location inside the kernel source.
+///
+/// The kernel source is a string literal or unquoted CUDA code.
+/// Unquoted code is cut out of the Rust file as written, so comments, `#include`, `#pragma` and the line structure are kept
+/// and compiler errors point at the Rust line. It has to consist of valid Rust tokens, e.g. balanced brackets and no `'ab'`.
+///
+/// Besides the bare source, `cuda!` accepts compiler options as `key = value` pairs:
+///
+/// - `src`: the kernel source, a string literal or unquoted code in braces (`src = { ... }`)
+/// - `path`: a kernel file instead of `src`, see [`cuda_file!`]
+/// - `arch`: the target architecture, e.g. `"sm_80"`
+/// - `opt_level`: passed as `-O<opt_level>`
+/// - `defines`: preprocessor definitions, e.g. `{ BLOCK = 256, USE_FAST }`
+/// - `include_dirs`: include paths relative to the crate root, e.g. `["kernels/include"]`
+/// - `flags`: additional nvcc flags, e.g. `["--use_fast_math"]`
+/// - `types`: Rust datatypes the placeholder `T` is instantiated with, e.g. `[f32, f64, i32]`
+/// - `module`: expands to a module with the PTX and kernel metadata, see below
+/// - `resource_usage`: collects registers and shared memory per kernel with `ptxas -v` (`CUSTOS_CUDA_PTXAS`)
+/// - `source_map`: maps the PTX lines of a `module` to the kernel source lines, see below
+/// - `hot_reload`: reloads the kernel file in debug builds, see below
+/// - `target`: `"cuda"` (default) or `"hip"`, see [`hip!`]
+///
+/// Crate-wide defaults are read from `CUSTOS_CUDA_ARCH`, `CUSTOS_CUDA_OPT_LEVEL`, `CUSTOS_CUDA_DEFINES`,
+/// `CUSTOS_CUDA_INCLUDE_DIRS` and `CUSTOS_CUDA_FLAGS`.
+///
+/// The compiler is selected with `CUSTOS_CUDA_COMPILER`: `nvcc` (default), `clang`,
+/// or a path to an executable that is invoked like nvcc (`<compiler> -c --ptx [flags] <input.cu> -o <output.ptx>`).
+/// The latter allows expanding `cuda!` on machines without a CUDA toolkit, e.g. with a stub that writes fixed PTX.
+///
+/// With `source_map = true` and `module`, kernels are compiled with `-lineinfo` (clang: `-gline-tables-only`)
+/// and the `.loc` directives of the PTX are recorded in `name::SOURCE_MAP`: one `name::SourceLine` per PTX line
+/// where the source line changes, with the kernel (entry) name and the Rust file and line of the literal,
+/// or the line of the kernel file or header. `name::source_line(name::SOURCE_MAP, ptx_line)` looks up a PTX line.
+/// This lets error paths and profilers print e.g. `kernel add_f32, src/ops/add.rs:42` instead of a PTX offset.
+///
+/// With `types`, every instantiation is compiled on its own, `T` is replaced by the matching C type (`f32` -> `float`).
+/// `cuda!` then expands to a lookup with a `get::<T>()` method, which returns the kernel for a listed datatype.
+///
+/// With `module = name`, `cuda!` is used in item position and generates `pub mod name`.
+/// It contains `fn ptx()`, a `Kernel` const with the entry name, the PTX parameter types and the resource usage
+/// for every `.entry` (e.g. `name::ADD`) and a `KERNELS` slice.
+/// Every `extern "C" __global__` kernel also gets a typed launch function that takes the device, a `LaunchConfig`
+/// and the kernel arguments (`float*` -> `&Buffer<f32, CUDA>`, `int` -> `i32`) and launches the kernel of `ptx()`,
+/// e.g. `name::add(&device, name::LaunchConfig::for_len(len, 256), &lhs, &rhs, &out, len as i32)`.
+/// The launch functions load the embedded PTX with `custos::cuda::api::load_module_data` on the first launch
+/// on a device (once per thread) and launch the kernel with `custos::cuda::api::culaunch_kernel` on the stream of the device.
+/// Kernels with parameters that have no Rust counterpart (structs, pointers to pointers) get a warning instead.
+/// Combined with `types`, these items are generated per datatype (e.g. `name::f32::ADD`) and `name::ptx::<T>()` looks up the PTX.
+///
+/// With `CUSTOS_CUDA_MODE=runtime` or the `runtime-compile` feature, `cuda!` does not compile anything.
+/// It expands to a `custos::cuda::KernelSrc` with `mode: KernelMode::Runtime`, the source and the NVRTC options instead,
+/// so the kernel can be compiled at runtime.
+///
+/// For reproducible releases, the PTX can be vendored, i.e. checked into the repository.
+/// `CUSTOS_CUDA_VENDOR=regenerate` compiles the kernels and writes the PTX into `CUSTOS_CUDA_VENDOR_DIR`
+/// (relative to the crate root, default: `ptx`), one file per kernel named after a hash of the source, the headers and the options.
+/// With `CUSTOS_CUDA_VENDOR=verify`, the PTX is loaded from there without invoking a compiler.
+/// If the source changed but the PTX was not regenerated, the build fails with the missing file and the outdated one.
+///
+/// With `hot_reload = true` and a kernel file, `cuda!` expands to a `custos_macro_build::HotReload` instead,
+/// which requires `custos-macro-build` as a dependency.
+/// In debug builds, it holds the path and the embedded PTX: `get()` checks the modification time of the file
+/// and recompiles it with the same options (and the compiler in `CUSTOS_CUDA_COMPILER`) when it changed.
+/// If the changed kernel does not compile, the previous PTX is kept and `last_error()` returns the error.
+/// In runtime mode, the changed source is returned for NVRTC. Release builds only embed the PTX or source.
+///
+/// # Example
+///
+/// ```ignore
+/// let ptx = cuda!(r#"
+///     extern "C" __global__ void add(float* lhs, float* rhs, float* out, int len) {
+///         int idx = blockDim.x * blockIdx.x + threadIdx.x;
+///         if (idx < len) {
+///             out[idx] = lhs[idx] + rhs[idx];
+///         }
+///     }
+/// "#);
+///
+/// let ptx = cuda!(
+///     #include "common.h"
+///
+///     extern "C" __global__ void scale(float* x, float factor, int len) {
+///         #pragma unroll 4
+///         for (int i = 0; i < len; i++) {
+///             x[i] *= factor; // unquoted, the comment is kept
+///         }
+///     }
+/// );
+///
+/// let ptx = cuda!(
+///     arch = "sm_80",
+///     defines = { BLOCK = 256 },
+///     flags = ["--use_fast_math"],
+///     src = r#"
+///         extern "C" __global__ void fill(float* out, float value) {
+///             out[blockIdx.x * BLOCK + threadIdx.x] = value;
+///         }
+///     "#
+/// );
+///
+/// fn add<T: CDatatype>(device: &CUDA, lhs: &Buffer<T, CUDA>, rhs: &Buffer<T, CUDA>) {
+///     let ptx = cuda!(types = [f32, f64, i32], src = r#"
+///         extern "C" __global__ void add(T* lhs, T* rhs, T* out, int len) { /* ... */ }
+///     "#)
+///     .get::<T>()
+///     .expect("unsupported datatype");
+///     // ...
+/// }
+///
+/// cuda!(module = add_kernels, resource_usage = true, src = r#"
+///     extern "C" __global__ void add(float* lhs, float* rhs, float* out, int len) { /* ... */ }
+/// "#);
+///
+/// const _: () = assert!(add_kernels::ADD.params.len() == 4);
+///
+/// add_kernels::add(&device, add_kernels::LaunchConfig::for_len(len, 256), &lhs, &rhs, &out, len as i32)?;
+/// ```
 #[proc_macro]
 pub fn cuda(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    // let input = syn::parse_macro_input!(input as LitStr).value();
-    let input = match syn::parse::<LitStr>(input.clone()) {
-        Ok(syntax_tree) => syntax_tree.value(),
-        Err(_) => input.to_string(),
-    };
-    // let input = input.to_string();
-
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    input.hash(&mut hasher);
-    let hash = hasher.finish();
-
-    let input_file_path = format!("./target/{hash}.cu");
+    proc_macro::TokenStream::from(cuda_expansion(input.into(), false))
+}
 
-    let out_file_path = format!("./target/{hash}.ptx");
+/// Compiles a CUDA file to PTX at compile time, like [`cuda!`].
+///
+/// The path is relative to the crate root (`CARGO_MANIFEST_DIR`).
+/// Local headers (`#include "..."`) are resolved relative to the including file and the `include_dirs`.
+/// The file and all its headers are tracked, cargo recompiles the crate if one of them changes.
+///
+/// Accepts the same options as [`cuda!`], with `path` instead of `src`.
+///
+/// # Example
+///
+/// ```ignore
+/// let ptx = cuda_file!("kernels/gemm.cu");
+///
+/// let ptx = cuda_file!(path = "kernels/gemm.cu", arch = "sm_80", defines = { TILE = 16 });
+///
+/// // recompiled at runtime in debug builds whenever the file changes
+/// let gemm = cuda_file!(path = "kernels/gemm.cu", hot_reload = true);
+/// let ptx = gemm.get();
+/// ```
+#[proc_macro]
+pub fn cuda_file(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    proc_macro::TokenStream::from(cuda_expansion(input.into(), true))
+}
 
-    std::fs::write(&input_file_path, input.as_bytes()).unwrap();
+/// Translates a CUDA kernel to HIP for AMD GPUs, same as `cuda!(target = "hip", ...)`.
+///
+/// The translation is a hipify-like text replacement: `cudaXxx` -> `hipXxx` (also `curand`, `cublas`, `cufft`),
+/// CUDA headers -> HIP headers (`cuda_fp16.h` -> `hip/hip_fp16.h`) and macros and types like `__CUDA_ARCH__` or `__nv_bfloat16`.
+/// Comments and literals are kept and every line stays on its line, so compiler errors map back to the CUDA source.
+/// Local headers are not translated.
+///
+/// If `CUSTOS_HIP_COMPILER` (e.g. `hipcc`) is set, the kernel is compiled with `--genco` to a `custos::hip::CodeObject`.
+/// The architecture is `arch` if it starts with `gfx`, otherwise `CUSTOS_HIP_ARCH`, extra flags are read from `CUSTOS_HIP_FLAGS`.
+/// Otherwise, or in runtime mode, it expands to a `custos::hip::HipSrc` with the translated source, which is compiled at runtime.
+/// `defines` and pre-included headers are then prepended to the source as `#define` and `#include` directives.
+///
+/// Accepts the same input as [`cuda!`], apart from `module`, `resource_usage` and `source_map`.
+///
+/// # Example
+///
+/// ```ignore
+/// let kernel = hip!(r#"
+///     #include <cuda_fp16.h>
+///     extern "C" __global__ void add(__half* lhs, __half* rhs, __half* out, int len) { /* ... */ }
+/// "#);
+///
+/// let kernel = cuda!(target = "hip", arch = "gfx90a", path = "kernels/add.cu");
+/// ```
+#[proc_macro]
+pub fn hip(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    proc_macro::TokenStream::from(hip_expansion(input.into()))
+}
 
-    let out = Command::new("nvcc")
-        .arg("-c")
-        .arg(input_file_path)
-        .arg("-o")
-        .arg(&out_file_path)
-        .arg("--ptx")
-        .output()
-        .unwrap();
+/// Checks OpenCL C source code at compile time.
+///
+/// Unterminated literals and comments and unbalanced brackets are reported at their location in the kernel.
+/// If `CUSTOS_OPENCL_CLANG` names a clang, the source is checked with `-cl-std=CL2.0 -fsyntax-only` as well
+/// (extra flags in `CUSTOS_OPENCL_FLAGS`).
+/// Accepts a string literal, unquoted OpenCL C, or `src`, `path` (relative to the crate root) and `module` as `key = value` pairs.
+///
+/// Expands to the checked source (`&'static str`).
+/// With `module = name`, it expands to `pub mod name` instead, containing `SOURCE` and a launch function per `__kernel`.
+/// `__global` and `__constant` pointers become `&custos::Buffer<'_, T, custos::OpenCL>`, scalars are passed by value.
+/// `SOURCE` is built on the first launch on a device (once per thread) and the launch functions
+/// pick their kernel from the program by name.
+///
+/// `opencl!(spirv, ...)` compiles the kernel offline with `clang --target=spirv64` and expands to a
+/// `custos::opencl::ClProgram::Il` with the SPIR-V, for `clCreateProgramWithIL`.
+/// Without `CUSTOS_OPENCL_CLANG`, or if clang cannot emit SPIR-V, it falls back to a `custos::opencl::ClProgram::Source`.
+/// Output that is not a SPIR-V module (e.g. SPIR bitcode of an older clang) is an error.
+/// The module form contains the program as `PROGRAM`, the launch functions still enqueue the source.
+///
+/// `opencl!(path = "...", hot_reload = true)` expands to a `custos_macro_build::HotReload` of the source,
+/// which rereads and checks the file in debug builds when it changes, see [`cuda!`].
+///
+/// # Example
+///
+/// ```ignore
+/// opencl!(module = kernels, src = r#"
+///     __kernel void add(__global const float* lhs, __global const float* rhs, __global float* out) {
+///         size_t i = get_global_id(0);
+///         out[i] = lhs[i] + rhs[i];
+///     }
+/// "#);
+///
+/// kernels::add(&device, [lhs.len(), 0, 0], None, &lhs, &rhs, &out)?;
+///
+/// let program = opencl!(spirv, path = "kernels/add.cl");
+/// ```
+#[proc_macro]
+pub fn opencl(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    proc_macro::TokenStream::from(opencl_expansion(input.into()))
+}
 
-    let stderr_utf8 = std::str::from_utf8(&out.stderr).unwrap();
-    if !out.stderr.is_empty() {
-        panic!("{stderr_utf8}")
-    }
+/// Parses and validates a WGSL shader with naga at compile time.
+///
+/// Syntax and validation errors (types, uniformity, bindings) are reported at their location in the shader.
+/// Accepts a string literal, unquoted WGSL, or `src` (a literal or `{ ... }`), `path` (relative to the crate root),
+/// `module` and `capabilities` as `key = value` pairs.
+///
+/// The shader is validated for the features every wgpu adapter supports. Optional features like `f64` or push
+/// constants need the naga capability in snake case, e.g. `capabilities = ["float64", "immediates"]`,
+/// and the matching wgpu feature at runtime.
+///
+/// Expands to the validated source (`&'static str`).
+/// With `module = name`, it expands to `pub mod name` instead, containing `SOURCE`,
+/// an `EntryPoint` const per entry point (e.g. `name::MAIN`) with its stage, workgroup size, the bindings
+/// and the push constant block it uses, and `ENTRY_POINTS`.
+///
+/// Requires the `wgsl` feature.
+///
+/// # Example
+///
+/// ```ignore
+/// let src: &str = wgsl!(r#"
+///     @group(0) @binding(0) var<storage, read_write> out: array<f32>;
+///
+///     @compute @workgroup_size(64)
+///     fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+///         out[id.x] = 1.0;
+///     }
+/// "#);
+///
+/// wgsl!(module = fill, path = "shaders/fill.wgsl");
+/// const _: () = assert!(fill::MAIN.workgroup_size[0] == 64);
+/// ```
+#[cfg(feature = "wgsl")]
+#[proc_macro]
+pub fn wgsl(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    proc_macro::TokenStream::from(wgsl_expansion(input.into()))
+}
 
-    let ptx_src = std::fs::read_to_string(out_file_path).unwrap();
+/// Compiles a WGSL or GLSL compute shader to SPIR-V with naga at compile time.
+///
+/// Takes the same input as [`wgsl!`], plus `lang = "wgsl"` or `lang = "glsl"`.
+/// Without `lang`, GLSL is detected by the file extension (`.comp`, `.glsl`) or a leading `#version` directive.
+/// Errors are reported at their location in the shader.
+///
+/// Expands to the SPIR-V module (`&'static [u32]`), which only depends on the shader source.
+/// With `module = name`, it expands to `pub mod name` containing `SPIRV` and the reflection data of [`wgsl!`].
+///
+/// Requires the `spirv` feature.
+///
+/// # Example
+///
+/// ```ignore
+/// let spirv: &[u32] = spirv!(capabilities = ["immediates"], src = r#"
+///     #version 450
+///     layout(local_size_x = 64) in;
+///     layout(set = 0, binding = 0) buffer Out { float out_[]; };
+///     layout(push_constant) uniform Params { float value; } params;
+///
+///     void main() {
+///         out_[gl_GlobalInvocationID.x] = params.value;
+///     }
+/// "#);
+///
+/// spirv!(module = fill, path = "shaders/fill.comp");
+/// const _: () = assert!(fill::MAIN.push_constant.unwrap().size == 4);
+/// ```
+#[cfg(feature = "spirv")]
+#[proc_macro]
+pub fn spirv(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    proc_macro::TokenStream::from(spirv_expansion(input.into()))
+}
 
-    quote!(
-        custos::cuda::Ptx {
-            src: #ptx_src.to_string()
-        }
-    )
-    .into()
-    // ptx_src.to_token_stream().into()
+/// Embeds every kernel of a directory as a module.
+///
+/// The directory is searched recursively (hidden entries are skipped), the path is relative to the crate root.
+/// `.cu` files are compiled to PTX like [`cuda_file!`], `.cl` files are checked lexically
+/// (unterminated literals and comments, unbalanced brackets) and embedded as source.
+/// Every file becomes a `&str` const named after its relative path (`nn/relu.cu` -> `NN_RELU_CU`).
+/// `KERNELS` and `SOURCES` list all files with their PTX or source, `kernel(name)` and `source(name)` look them up by file name.
+///
+/// The module is named after the directory or set with `module = name`.
+/// The CUDA options of [`cuda!`] apply to every `.cu` file.
+/// All files and their headers are tracked, cargo recompiles the crate if one of them changes.
+///
+/// # Example
+///
+/// ```ignore
+/// include_kernels!("kernels/");
+///
+/// let ptx = kernels::NN_RELU_CU;
+/// let src = kernels::source("blas/gemm.cl").unwrap();
+///
+/// include_kernels!(path = "src/kernels", module = gpu_kernels, arch = "sm_80");
+/// ```
+#[proc_macro]
+pub fn include_kernels(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    proc_macro::TokenStream::from(include_kernels_expansion(input.into()))
 }
 
 /// Expands a `CPU` implementation to a `Stack` and `CPU` implementation.
 ///
+/// The `Stack` copy replaces every type path segment `CPU` with `Stack`, e.g. `Buffer<T, CPU, S>`, `custos::CPU`
+/// or `CPU::new()`. Other identifiers (`CPUBuffer`, `MAX_CPU_THREADS`), string literals and macro invocations are kept.
+/// The copy keeps the spans of the original, so errors in the `Stack` implementation point at the original line.
+///
+/// The `CPU` implementation is compiled with `#[cfg(feature = "cpu")]`, the `Stack` copy with `#[cfg(feature = "stack")]`.
+/// These features are looked up in the crate that uses `#[impl_stack]` and can be changed:
+///
+/// - `cpu_feature = "..."`, `stack_feature = "..."`: other feature names
+/// - `cpu_cfg = ...`, `stack_cfg = ...`: any cfg predicate, e.g. `stack_cfg = all(feature = "stack", not(miri))`
+/// - `no_cfg`: no `#[cfg]` at all, both implementations are always compiled
+///
+/// With `const_n`, the `Stack` copy gets a `const N: usize` parameter and a `Stack: custos::Alloc<T, N>` bound,
+/// where `T` is the first type parameter. `N` is appended to the trait, which therefore needs a trailing
+/// `const N: usize` parameter, and to every `Buffer` of `Stack` in the trait arguments, where clauses, method signatures
+/// and bodies (`Buffer<T, Stack, S>` -> `Buffer<T, Stack, S, N>`), also inside other types like `Option<Buffer<..>>`.
+/// Buffers inside macro invocations and in the `#[stack(...)]` bodies and bounds are not changed, these can use `N` directly.
+/// This allows `Stack` operations whose output length differs from the input shape.
+///
+/// Attributes on the items of the impl block adjust the `Stack` copy:
+///
+/// - `#[stack(skip)]`: leaves the item out of the `Stack` copy, e.g. to use the default method of the trait
+/// - `#[stack(body = { ... })]`: the method body of the `Stack` copy, e.g. instead of a BLAS call or a `Vec` scratch buffer
+/// - `#[cpu_only]`, `#[stack_only]`: the item is only part of one implementation, e.g. two versions of a method
+/// - `#[stack(where T: Copy)]`: bounds that are only added to the `Stack` copy, on the impl block itself
+///   or on a method of an inherent impl. The methods of a trait impl cannot have stricter bounds than the trait.
+///
+/// Options can be combined, `where` comes last: `#[stack(body = { ... }, where T: Copy)]`.
+///
 /// # Example
 ///
 /// ```ignore
@@ -125,34 +448,96 @@ pub fn cuda(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 ///
 /// // Now is it possible to execute this operations with a CPU and Stack device.
 ///
+/// #[impl_stack(cpu_feature = "host", stack_cfg = any(feature = "stack", feature = "no-std"))]
+/// impl<T: Number> Sum<T> for CPU { /* ... */ }
+///
+/// #[impl_stack(no_cfg)]
+/// impl<T: Number> Mean<T> for CPU { /* ... */ }
+///
+/// #[impl_stack]
+/// #[stack(where T: Copy)]
+/// impl<T: Number> Gemm<T> for CPU {
+///     #[stack(body = { naive_gemm(lhs, rhs, out) })]
+///     fn gemm(&self, lhs: &[T], rhs: &[T], out: &mut [T]) {
+///         blas_gemm(lhs, rhs, out)
+///     }
+///
+///     #[stack(skip)]
+///     fn name(&self) -> &'static str { "blas" }
+///
+///     #[cpu_only]
+///     fn scratch_len(&self) -> usize { Vec::<T>::with_capacity(64).capacity() }
+///
+///     #[stack_only]
+///     fn scratch_len(&self) -> usize { 0 }
+/// }
+///
+/// #[impl_stack(const_n)]
+/// impl<T: Number, S: Shape> Concat<T, CPU, S> for CPU {
+///     fn concat(&self, bufs: &[&Buffer<T, CPU, S>]) -> Buffer<T, CPU, S> { /* ... */ }
+/// }
 /// ```
 #[proc_macro_attribute]
 pub fn impl_stack(
-    _attr: proc_macro::TokenStream,
+    attr: proc_macro::TokenStream,
     item: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
+    let args = parse_macro_input!(attr as ImplStackArgs);
     let input = parse_macro_input!(item as ItemImpl);
-    proc_macro::TokenStream::from(add_stack_impl_simple(input))
+    proc_macro::TokenStream::from(impl_stack_expansion(args, input))
 }
 
-const ERROR_MSG: &str = "Can't use #[impl_stack] on this implement block.";
-
-fn add_stack_impl_simple(impl_block: ItemImpl) -> proc_macro2::TokenStream {
-    let stack_impl_block = impl_block
-        .to_token_stream()
-        .to_string()
-        .replace("CPU", "Stack");
-
-    let stack_impl_block: proc_macro2::TokenStream =
-        syn::parse_str(&stack_impl_block).expect(ERROR_MSG);
-
-    quote!(
-        #[cfg(feature = "cpu")]
-        #impl_block
-
-        #[cfg(feature = "stack")]
-        #stack_impl_block
-    )
+/// Expands a `CPU` implementation to the `CPU` implementation and one copy per listed device.
+///
+/// Every copy replaces the `CPU` type paths with the device, like [`macro@impl_stack`] does with `Stack`,
+/// and is compiled with a cargo feature: `#[cfg(feature = "cpu")]` for the original, the feature after `=>` for a device,
+/// or the device name in snake case (`MyCpuWrapper` -> `my_cpu_wrapper`). `=> cfg(...)` takes any cfg predicate instead.
+/// A device name replaces only the `CPU` segment (`custos::CPU` -> `custos::Stack`), a device path the whole path.
+///
+/// The condition of the `CPU` implementation is set like for [`macro@impl_stack`]:
+///
+/// - `cpu_feature = "..."`: another feature name
+/// - `cpu_cfg = ...`: any cfg predicate, e.g. `cpu_cfg = all(feature = "cpu", not(miri))`
+/// - `no_cfg`: no `#[cfg]` for the `CPU` implementation and the devices, which then cannot have a `=>` condition
+///
+/// The item attributes of `#[impl_stack]` (`#[stack(...)]`, `#[cpu_only]`, `#[stack_only]`) and `const_n` are not available,
+/// for impl blocks without them `#[impl_stack]` expands like `#[impl_for_devices(Stack)]`.
+///
+/// # Example
+///
+/// ```ignore
+/// #[impl_for_devices(Stack, MyCpuWrapper, custos::OpenCL => "opencl")]
+/// impl<T: Number, S: Shape> ElementWise<T, CPU, S> for CPU {
+///     fn add(&self, lhs: &Buffer<T, CPU, S>, rhs: &Buffer<T, CPU, S>) -> Buffer<T, CPU, S> {
+///         // ...
+///     }
+/// }
+///
+/// // expands to
+///
+/// #[cfg(feature = "cpu")]
+/// impl<T: Number, S: Shape> ElementWise<T, CPU, S> for CPU { /* ... */ }
+///
+/// #[cfg(feature = "stack")]
+/// impl<T: Number, S: Shape> ElementWise<T, Stack, S> for Stack { /* ... */ }
+///
+/// #[cfg(feature = "my_cpu_wrapper")]
+/// impl<T: Number, S: Shape> ElementWise<T, MyCpuWrapper, S> for MyCpuWrapper { /* ... */ }
+///
+/// #[cfg(feature = "opencl")]
+/// impl<T: Number, S: Shape> ElementWise<T, custos::OpenCL, S> for custos::OpenCL { /* ... */ }
+///
+/// #[impl_for_devices(cpu_feature = "host", Stack)]
+/// impl<T: Number> Sum<T> for CPU { /* ... */ }
+/// ```
+#[proc_macro_attribute]
+pub fn impl_for_devices(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let args = parse_macro_input!(attr as ImplForDevicesArgs);
+    let input = parse_macro_input!(item as ItemImpl);
+    proc_macro::TokenStream::from(impl_for_devices_expansion(args, input))
 }
 
 #[proc_macro_attribute]
@@ -188,12 +573,13 @@ fn add_stack_cpu_test(input: ItemFn) -> proc_macro2::TokenStream {
 /// Implements a custos operation trait for the custos `NNapiDevice` using an array of `OperationCode`.
 /// Does not support constants or type definitions.
 /// The output shape should be determined by "OS" or "S".
+/// The example needs custos with the `nnapi` feature and is not compiled as a doctest.
 ///
 /// # Example
 ///
 /// // --- before ---
 ///
-/// ```
+/// ```ignore
 /// pub trait BinaryElementWise<T, S: Shape = (), D: Device = Self>: Device {
 ///     fn add(&self, lhs: &Buffer<T, D, S>, rhs: &Buffer<T, D, S>) -> Buffer<T, D, S>;
 ///     fn mul(&self, lhs: &Buffer<T, D, S>, rhs: &Buffer<T, D, S>) -> Buffer<T, D, S>;
@@ -280,107 +666,3 @@ pub fn add_op(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(item as ExprCall);
     proc_macro::TokenStream::from(add_op_expansion(input))
 }
-
-/*
-
-fn add_stack_impl(impl_block: ItemImpl) -> proc_macro2::TokenStream {
-    let attrs = impl_block.attrs.iter().fold(quote!(), |mut acc, attr| {
-        acc.extend(attr.to_token_stream());
-        acc
-    });
-    let spawn_generics = impl_block.generics.params.to_token_stream();
-    let where_clause = impl_block.generics.where_clause.as_ref().unwrap();
-
-    if let Some(generic_type) = impl_block.generics.type_params().next() {
-        let generic_ident = &generic_type.ident;
-        /*if generic_type.ident != "T" {
-            panic!("{ERROR_MSG}");
-            //panic!("--> should use the datatype provided from ...? e.g. #[impl_stack(f32)]");
-        }*/
-
-        let impl_trait = &impl_block
-            .trait_
-            .as_ref()
-            .expect(ERROR_MSG)
-            .1
-            .to_token_stream()
-            .to_string();
-        let mut path_generics = impl_trait.split('<');
-
-        let trait_name = path_generics.next().expect(ERROR_MSG);
-        let generics_no_const = path_generics.next().expect(ERROR_MSG);
-        let trait_generics = format!(
-            "{}<{}, N >",
-            trait_name,
-            &generics_no_const[..generics_no_const.len() - 2]
-        );
-
-        let trait_path: Path = syn::parse_str(&trait_generics).expect(ERROR_MSG);
-
-        //let generics = remove_lit(generics);
-
-        let methods_updated = impl_block
-            .items
-            .clone()
-            .into_iter()
-            .flat_map(|item| match item {
-                syn::ImplItem::Method(method) => Some(method),
-                _ => None,
-            })
-            .fold(quote!(), |mut acc, mut meth| {
-                if let ReturnType::Type(_, output) = &mut meth.sig.output {
-                    *output = insert_const_n_to_buf(output.to_token_stream());
-                }
-
-                meth.sig.inputs = meth
-                    .sig
-                    .inputs
-                    .iter_mut()
-                    .map(|input| {
-                        match input.clone() {
-                            // self
-                            syn::FnArg::Receiver(_) => input.clone(),
-                            // other args
-                            syn::FnArg::Typed(typed) => {
-                                insert_const_n_to_buf(typed.to_token_stream())
-                            }
-                        }
-                    })
-                    .collect();
-
-                acc.extend(meth.to_token_stream());
-                acc
-            });
-
-        //panic!("methods: {}", methods_updated.to_token_stream().to_string());
-
-        return quote! {
-            #impl_block
-
-            #[cfg(feature = "stack")]
-            #attrs
-            impl<#spawn_generics, const N: usize> #trait_path for custos::stack::Stack
-            #where_clause
-            custos::stack::Stack: custos::Alloc<#generic_ident, N>
-            {
-                #methods_updated
-            }
-        };
-        //panic!("x: {}", x.to_string());
-    }
-    panic!("{ERROR_MSG}")
-}
-
-fn insert_const_n_to_buf<R: syn::parse::Parse + Clone>(tokens: proc_macro2::TokenStream) -> R {
-    let tokens = tokens.to_string();
-    if !tokens.contains("Buffer") {
-        return syn::parse_str(&tokens).unwrap();
-    }
-    let mut tokens = tokens.replace("CPU", "Stack");
-
-    let idx = tokens.find('>').unwrap();
-    tokens.insert_str(idx - 1, ", N ");
-    syn::parse_str(&tokens).unwrap()
-}
-
-*/
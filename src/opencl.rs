@@ -0,0 +1,417 @@
+use custos_macro_build::{
+    cuda::has_errors,
+    opencl::{run_clang, validate_opencl, Clang, ClangOutput, OPENCL_ENV_VARS},
+    signatures::{parse_opencl_kernel_signatures, KernelSignature},
+};
+use proc_macro2::{Literal, Span, TokenStream};
+use quote::{quote, quote_spanned};
+use syn::{
+    braced,
+    parse::{Parse, ParseStream},
+    token, Ident, LitBool, Token,
+};
+
+use crate::{
+    dtype::rust_type_of_opencl,
+    hot_reload::hot_reload_handle,
+    kernel_src::{emit_warning, KernelSrc},
+    launch::{c_signature, launch_fn_ident, launch_params},
+    options::{track_env_vars, InputForm},
+};
+
+/// Names of the wrapper arguments that precede the kernel arguments.
+const RESERVED: &[&str] = &["device", "gws", "lws"];
+
+/// The input of `opencl!`: a bare string literal, `key = value` pairs or unquoted kernel code,
+/// optionally preceded by the `spirv` flag.
+pub struct OpenClInput {
+    pub kernel: KernelSrc,
+    /// Expands to a module with launch functions instead of an expression.
+    pub module: Option<Ident>,
+    /// Compiles the kernel to SPIR-V with clang, if one is configured.
+    pub spirv: bool,
+    /// Expands to a `custos_macro_build::HotReload` that reloads the kernel file in debug builds.
+    pub hot_reload: bool,
+}
+
+impl OpenClInput {
+    pub fn parse(input: TokenStream) -> syn::Result<OpenClInput> {
+        let mut tokens = input.clone().into_iter();
+        let spirv = match (tokens.next(), tokens.next()) {
+            (Some(proc_macro2::TokenTree::Ident(flag)), None) if flag == "spirv" => {
+                return Err(syn::Error::new(
+                    flag.span(),
+                    "missing kernel source after `spirv`, e.g. `opencl!(spirv, src = r\"...\")`",
+                ))
+            }
+            (
+                Some(proc_macro2::TokenTree::Ident(flag)),
+                Some(proc_macro2::TokenTree::Punct(comma)),
+            ) if flag == "spirv" && comma.as_char() == ',' => true,
+            _ => false,
+        };
+        let input = match spirv {
+            true => input.into_iter().skip(2).collect(),
+            false => input,
+        };
+
+        let mut input = OpenClInput::parse_source(input)?;
+        input.spirv = spirv;
+        Ok(input)
+    }
+
+    fn parse_source(input: TokenStream) -> syn::Result<OpenClInput> {
+        let kernel = match InputForm::parse(input)? {
+            InputForm::Lit(lit) => KernelSrc::from_lit(lit),
+            InputForm::KeyValues(input) => return Ok(input),
+            InputForm::Code(kernel) => kernel,
+        };
+        Ok(OpenClInput {
+            kernel,
+            module: None,
+            spirv: false,
+            hot_reload: false,
+        })
+    }
+}
+
+impl Parse for OpenClInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut src = None;
+        let mut module = None;
+        let mut hot_reload = false;
+
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+
+            match key.to_string().as_str() {
+                "src" if input.peek(token::Brace) => {
+                    let content;
+                    let brace = braced!(content in input);
+                    src = Some(KernelSrc::from_tokens(content.parse()?, brace.span.join()));
+                }
+                "src" => src = Some(KernelSrc::from_lit(input.parse()?)),
+                "path" => src = Some(KernelSrc::from_file(&input.parse()?)?),
+                "module" => module = Some(input.parse()?),
+                "hot_reload" => hot_reload = input.parse::<LitBool>()?.value,
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "unknown option, expected one of `src`, `path`, `module`, `hot_reload`",
+                    ))
+                }
+            }
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+
+        let kernel = src.ok_or_else(|| {
+            syn::Error::new(
+                Span::call_site(),
+                "missing kernel source, e.g. `src = r\"...\"` or `path = \"kernels/add.cl\"`",
+            )
+        })?;
+
+        Ok(OpenClInput {
+            kernel,
+            module,
+            spirv: false,
+            hot_reload,
+        })
+    }
+}
+
+/// Expands `opencl!`.
+///
+/// The source is checked lexically and, if `CUSTOS_OPENCL_CLANG` is set, with `clang -cl-std=CL2.0 -fsyntax-only`.
+/// The expression form expands to the checked source (`&'static str`),
+/// `module = name` expands to a module with the source and a launch function per `__kernel`.
+///
+/// With the `spirv` flag, the kernel is compiled with `clang --target=spirv64` instead and embedded as a
+/// `custos::opencl::ClProgram::Il`. Without clang, it falls back to a `custos::opencl::ClProgram::Source`.
+///
+/// With `hot_reload = true`, the expression form expands to a `custos_macro_build::HotReload` of the source instead.
+pub fn opencl_expansion(input: TokenStream) -> TokenStream {
+    let OpenClInput {
+        kernel,
+        module,
+        spirv,
+        hot_reload,
+    } = match OpenClInput::parse(input) {
+        Ok(input) => input,
+        Err(err) => return err.to_compile_error(),
+    };
+    if hot_reload && (spirv || module.is_some()) {
+        let option = match spirv {
+            true => "`spirv`",
+            false => "`module`",
+        };
+        let message = format!("`hot_reload` cannot be combined with {option}");
+        return quote_spanned!(kernel.span()=> compile_error!(#message));
+    }
+    let mut tracked = track_env_vars(OPENCL_ENV_VARS);
+    tracked.extend(kernel.track_files());
+    let src = &kernel.src;
+
+    let lexical = validate_opencl(src);
+    let mut diagnostics = kernel.emit_diagnostics("opencl", &lexical);
+    let output = match spirv {
+        true => ClangOutput::Spirv,
+        false => ClangOutput::SyntaxOnly,
+    };
+    let mut il = None;
+    // clang would only repeat the lexical errors
+    if let Some(clang) = Clang::from_env().filter(|_| !has_errors(&lexical)) {
+        let include_dir = kernel.path().and_then(|path| path.parent());
+        diagnostics.extend(match run_clang(src, include_dir, &clang, output) {
+            Ok((bin, checked)) if output == ClangOutput::Spirv && bin.is_none() => {
+                match checked.iter().any(|diagnostic| diagnostic.line.is_some()) {
+                    true => kernel.emit_diagnostics("clang", &checked),
+                    // e.g. a clang without the SPIR-V target, the source still works
+                    false => {
+                        let message = format!(
+                            "custos-macro: could not compile the kernel to SPIR-V, embedding the source instead: {}",
+                            checked
+                                .iter()
+                                .map(|diagnostic| diagnostic.message.as_str())
+                                .collect::<Vec<_>>()
+                                .join("; ")
+                        );
+                        emit_warning(&message, kernel.span())
+                    }
+                }
+            }
+            Ok((bin, checked)) => {
+                il = bin;
+                kernel.emit_diagnostics("clang", &checked)
+            }
+            Err(message) => {
+                let message = format!("custos-macro: {message}");
+                quote_spanned!(kernel.span()=> compile_error!(#message);)
+            }
+        });
+    }
+
+    let program = match &il {
+        Some(il) => {
+            let il = Literal::byte_string(il);
+            quote!(custos::opencl::ClProgram::Il(#il))
+        }
+        None => quote!(custos::opencl::ClProgram::Source(#src)),
+    };
+
+    let Some(module) = module else {
+        let value = match (spirv, hot_reload) {
+            (true, _) => program,
+            (false, true) => {
+                hot_reload_handle(&kernel, src, quote!(::custos_macro_build::Reload::OpenCl))
+            }
+            (false, false) => quote!(#src),
+        };
+        return quote!({
+            #tracked
+            #diagnostics
+            #value
+        });
+    };
+
+    let (fns, warnings): (Vec<_>, Vec<_>) = parse_opencl_kernel_signatures(src)
+        .iter()
+        .map(|signature| match launch_fn(signature) {
+            Ok(launch_fn) => (launch_fn, quote!()),
+            Err(reason) => {
+                let message = format!("no launch function for `{}`: {reason}", signature.name);
+                (
+                    quote!(),
+                    emit_warning(&message, kernel.span_at(signature.line, 1)),
+                )
+            }
+        })
+        .unzip();
+
+    let program = spirv.then(|| {
+        quote! {
+            /// The program, SPIR-V if it was compiled with clang, otherwise the source.
+            /// The launch functions build `SOURCE`.
+            pub const PROGRAM: custos::opencl::ClProgram = #program;
+        }
+    });
+
+    let enqueue = enqueue_fn();
+
+    quote! {
+        #[allow(dead_code)]
+        pub mod #module {
+            #tracked
+
+            const _: () = {
+                #diagnostics
+                #(#warnings)*
+            };
+
+            /// The checked OpenCL C source.
+            pub const SOURCE: &str = #src;
+
+            #program
+
+            #enqueue
+
+            #(#fns)*
+        }
+    }
+}
+
+/// The `enqueue` function of a generated module, which the launch functions call with their kernel name.
+///
+/// `SOURCE` is built once per device and thread, the kernels are created from the program by name and kept with it.
+fn enqueue_fn() -> TokenStream {
+    quote! {
+        /// Sets the arguments of the kernel `name` of `SOURCE` and enqueues it.
+        fn enqueue(
+            device: &custos::OpenCL,
+            name: &'static str,
+            gws: [usize; 3],
+            lws: Option<[usize; 3]>,
+            args: &[&dyn custos::opencl::AsClCvoidPtr],
+        ) -> custos::Result<()> {
+            type Built = (custos::opencl::api::Program, Vec<(&'static str, custos::opencl::api::Kernel)>);
+            std::thread_local! {
+                // keyed by the address of the device, whose context owns the program
+                static PROGRAMS: std::cell::RefCell<Vec<(usize, Built)>> = const { std::cell::RefCell::new(Vec::new()) };
+            }
+
+            let key = device as *const custos::OpenCL as usize;
+            PROGRAMS.with(|programs| {
+                let mut programs = programs.borrow_mut();
+                let idx = match programs.iter().position(|(device, _)| *device == key) {
+                    Some(idx) => idx,
+                    None => {
+                        let program = custos::opencl::api::create_program_with_source(device.ctx(), SOURCE)?;
+                        custos::opencl::api::build_program(&program, &[device.device()], Some("-cl-std=CL2.0"))?;
+                        programs.push((key, (program, Vec::new())));
+                        programs.len() - 1
+                    }
+                };
+                let (program, kernels) = &mut programs[idx].1;
+                let idx = match kernels.iter().position(|(kernel, _)| *kernel == name) {
+                    Some(idx) => idx,
+                    None => {
+                        kernels.push((name, custos::opencl::api::create_kernel(program, name)?));
+                        kernels.len() - 1
+                    }
+                };
+                let kernel = &kernels[idx].1;
+
+                for (idx, arg) in args.iter().enumerate() {
+                    custos::opencl::api::set_kernel_arg(kernel, idx, arg.as_cvoid_ptr(), arg.ptr_size(), arg.is_num())?;
+                }
+                let work_dim = gws.iter().position(|&size| size == 0).unwrap_or(3).max(1);
+                custos::opencl::api::enqueue_nd_range_kernel(device.queue(), kernel, work_dim, &gws, lws.as_ref(), None)?;
+                Ok(())
+            })
+        }
+    }
+}
+
+/// A launch function that enqueues the kernel with a global and an optional local work size.
+fn launch_fn(signature: &KernelSignature) -> Result<TokenStream, String> {
+    let name = &signature.name;
+    let ident = launch_fn_ident(name)?;
+    if name == "enqueue" {
+        return Err(format!("the name is taken by `{name}()`"));
+    }
+    let params = launch_params(
+        signature,
+        RESERVED,
+        &quote!(custos::OpenCL),
+        rust_type_of_opencl,
+    )?;
+    let args = params.iter().map(|(ident, ty, _)| quote!(#ident: #ty));
+    let values = params.iter().map(|(_, _, value)| value);
+    let doc = format!("Enqueues `{}`.", c_signature(signature));
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[allow(clippy::too_many_arguments)]
+        pub fn #ident(
+            device: &custos::OpenCL,
+            gws: [usize; 3],
+            lws: Option<[usize; 3]>,
+            #(#args),*
+        ) -> custos::Result<()> {
+            enqueue(device, #name, gws, lws, &[#(#values),*])
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use custos_macro_build::signatures::parse_opencl_kernel_signatures;
+    use quote::quote;
+
+    use super::{launch_fn, opencl_expansion};
+
+    fn launch_fn_of(src: &str) -> Result<String, String> {
+        let signatures = parse_opencl_kernel_signatures(src);
+        launch_fn(&signatures[0]).map(|tokens| tokens.to_string())
+    }
+
+    #[test]
+    fn launch_fn_maps_buffers_scalars_and_reserved_names() {
+        let launch_fn = launch_fn_of(
+            "__kernel void scale(__global const float* x, __constant int* lut, float device, uint len) {}",
+        )
+        .unwrap();
+
+        let expected = quote! {
+            #[doc = "Enqueues `scale(float* x, int* lut, float device, uint len)`."]
+            #[allow(clippy::too_many_arguments)]
+            pub fn scale(
+                device: &custos::OpenCL,
+                gws: [usize; 3],
+                lws: Option<[usize; 3]>,
+                x: &custos::Buffer<'_, f32, custos::OpenCL>,
+                lut: &custos::Buffer<'_, i32, custos::OpenCL>,
+                arg2: f32,
+                len: u32
+            ) -> custos::Result<()> {
+                enqueue(device, "scale", gws, lws, &[x, lut, &arg2, &len])
+            }
+        };
+        assert_eq!(launch_fn, expected.to_string());
+    }
+
+    #[test]
+    fn no_launch_fn_for_local_memory_or_taken_names() {
+        let err = launch_fn_of("__kernel void reduce(__global float* x, __local float* tile) {}")
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "parameter `tile` is `__local` memory, which cannot be passed as a buffer"
+        );
+
+        let err = launch_fn_of("__kernel void enqueue(__global float* x) {}").unwrap_err();
+        assert_eq!(err, "the name is taken by `enqueue()`");
+    }
+
+    #[test]
+    fn module_embeds_the_source_once() {
+        let src = "__kernel void add(__global float* x) { x[0] += 1.0f; }\n__kernel void sub(__global float* x) { x[0] -= 1.0f; }\n";
+        let expanded = opencl_expansion(quote!(module = kernels, src = #src)).to_string();
+
+        assert_eq!(expanded.matches("x[0] += 1.0f").count(), 1, "{expanded}");
+        assert!(expanded.contains("pub const SOURCE : & str"), "{expanded}");
+        assert!(
+            expanded.contains("enqueue (device , \"add\" , gws , lws , & [x])"),
+            "{expanded}"
+        );
+        assert!(
+            expanded.contains("enqueue (device , \"sub\" , gws , lws , & [x])"),
+            "{expanded}"
+        );
+    }
+}
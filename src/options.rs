@@ -0,0 +1,279 @@
+use std::path::Path;
+
+pub use custos_macro_build::options::{resolve_path, CudaMode, CudaOptions, CUDA_ENV_VARS};
+use proc_macro2::{Span, TokenStream};
+use syn::{
+    braced, bracketed,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    token, Expr, Ident, Lit, LitBool, LitStr, Token, Type,
+};
+
+use crate::{
+    dtype::{c_type_of, CType},
+    kernel_src::KernelSrc,
+};
+
+/// Parses the value of a compiler option, e.g. `arch = "sm_80"`.
+/// Returns `false` if `key` is not a compiler option.
+pub fn parse_cuda_option(
+    options: &mut CudaOptions,
+    key: &Ident,
+    input: ParseStream,
+) -> syn::Result<bool> {
+    match key.to_string().as_str() {
+        "arch" => options.arch = Some(input.parse::<LitStr>()?.value()),
+        "opt_level" => options.opt_level = Some(lit_to_string(&input.parse()?)),
+        "defines" => {
+            let content;
+            braced!(content in input);
+            let defines = Punctuated::<Define, Token![,]>::parse_terminated(&content)?;
+            options.defines.extend(
+                defines
+                    .into_iter()
+                    .map(|define| (define.name, define.value)),
+            );
+        }
+        "include_dirs" => options
+            .include_dirs
+            .extend(parse_str_list(input)?.iter().map(resolve_path)),
+        "flags" => options.flags.extend(parse_str_list(input)?),
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Reads the variables with `option_env!` in the calling crate.
+/// rustc records them as dependencies, so cargo expands the macro again if one of them changes.
+pub fn track_env_vars(vars: &[&str]) -> TokenStream {
+    vars.iter()
+        .map(|var| {
+            quote::quote!(
+                const _: Option<&str> = option_env!(#var);
+            )
+        })
+        .collect()
+}
+
+/// The input of `cuda!`.
+///
+/// Either a bare string literal, `key = value` pairs or unquoted kernel code.
+pub struct CudaInput {
+    pub options: CudaOptions,
+    pub kernel: KernelSrc,
+    /// The datatypes `T` is instantiated with.
+    pub types: Vec<(Type, CType)>,
+    /// Expands to a module with kernel metadata instead of an expression.
+    pub module: Option<Ident>,
+    /// Collects register and shared memory usage with `ptxas -v`.
+    pub resource_usage: bool,
+    /// Compiles with `-lineinfo` and generates a source map of the PTX in the `module`.
+    pub source_map: bool,
+    /// Expands to a `custos_macro_build::HotReload` that reloads the kernel file in debug builds.
+    pub hot_reload: bool,
+    pub target: Target,
+}
+
+/// The GPU platform a kernel is compiled for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Cuda,
+    /// The kernel is translated to HIP for AMD GPUs.
+    Hip,
+}
+
+impl CudaInput {
+    /// Parses the input of `cuda!`.
+    /// A bare literal is the kernel source, or with `literal_is_path`, the path of a kernel file (`cuda_file!`).
+    pub fn parse(input: TokenStream, literal_is_path: bool) -> syn::Result<CudaInput> {
+        let mut input = match InputForm::parse(input)? {
+            InputForm::Lit(lit) => CudaInput::new(match literal_is_path {
+                true => KernelSrc::from_file(&lit)?,
+                false => KernelSrc::from_lit(lit),
+            }),
+            InputForm::KeyValues(input) => input,
+            InputForm::Code(kernel) => CudaInput::new(kernel),
+        };
+
+        // headers next to a kernel file are found without an explicit include dir
+        if let Some(dir) = input.kernel.path().and_then(Path::parent) {
+            input.options.include_dirs.insert(0, dir.to_path_buf());
+        }
+        input.kernel.resolve_includes(&input.options.include_dirs);
+
+        Ok(input)
+    }
+
+    fn new(kernel: KernelSrc) -> CudaInput {
+        CudaInput {
+            options: CudaOptions::from_env(),
+            kernel,
+            types: Vec::new(),
+            module: None,
+            resource_usage: false,
+            source_map: false,
+            hot_reload: false,
+            target: Target::Cuda,
+        }
+    }
+}
+
+/// The forms of the kernel macro inputs: a bare string literal, `key = value` pairs or unquoted kernel code.
+pub enum InputForm<T> {
+    Lit(LitStr),
+    /// The input starts with `ident =` and is parsed as `T`.
+    KeyValues(T),
+    Code(KernelSrc),
+}
+
+impl<T: Parse> InputForm<T> {
+    pub fn parse(input: TokenStream) -> syn::Result<InputForm<T>> {
+        if let Ok(lit) = syn::parse2::<LitStr>(input.clone()) {
+            return Ok(InputForm::Lit(lit));
+        }
+
+        let mut tokens = input.clone().into_iter();
+        let is_key_value = matches!(
+            (tokens.next(), tokens.next()),
+            (Some(proc_macro2::TokenTree::Ident(_)), Some(proc_macro2::TokenTree::Punct(punct))) if punct.as_char() == '='
+        );
+        match is_key_value {
+            true => syn::parse2(input).map(InputForm::KeyValues),
+            false => Ok(InputForm::Code(KernelSrc::from_tokens(
+                input,
+                Span::call_site(),
+            ))),
+        }
+    }
+}
+
+impl Parse for CudaInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = CudaOptions::from_env();
+        let mut src = None;
+        let mut types = Vec::new();
+        let mut module = None;
+        let mut resource_usage = false;
+        let mut source_map = false;
+        let mut hot_reload = false;
+        let mut target = Target::Cuda;
+
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+
+            match key.to_string().as_str() {
+                "src" if input.peek(token::Brace) => {
+                    let content;
+                    let brace = braced!(content in input);
+                    src = Some(KernelSrc::from_tokens(content.parse()?, brace.span.join()));
+                }
+                "src" => src = Some(KernelSrc::from_lit(input.parse()?)),
+                "path" => src = Some(KernelSrc::from_file(&input.parse()?)?),
+                "types" => {
+                    let content;
+                    bracketed!(content in input);
+                    for ty in Punctuated::<Type, Token![,]>::parse_terminated(&content)? {
+                        let c_type = c_type_of(&ty).ok_or_else(|| {
+                            syn::Error::new_spanned(&ty, "no C type is known for this type")
+                        })?;
+                        types.push((ty, c_type));
+                    }
+                }
+                "module" => module = Some(input.parse::<Ident>()?),
+                "resource_usage" => resource_usage = input.parse::<LitBool>()?.value,
+                "source_map" => source_map = input.parse::<LitBool>()?.value,
+                "hot_reload" => hot_reload = input.parse::<LitBool>()?.value,
+                "target" => {
+                    let lit = input.parse::<LitStr>()?;
+                    target = match lit.value().as_str() {
+                        "cuda" => Target::Cuda,
+                        "hip" => Target::Hip,
+                        _ => {
+                            return Err(syn::Error::new(
+                                lit.span(),
+                                "unknown target, expected `\"cuda\"` or `\"hip\"`",
+                            ))
+                        }
+                    };
+                }
+                _ if parse_cuda_option(&mut options, &key, input)? => {}
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "unknown option, expected one of `src`, `path`, `arch`, `opt_level`, `defines`, `include_dirs`, `flags`, `types`, `module`, `resource_usage`, `source_map`, `hot_reload`, `target`",
+                    ))
+                }
+            }
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+
+        let kernel = src.ok_or_else(|| {
+            syn::Error::new(
+                Span::call_site(),
+                "missing kernel source, e.g. `src = r\"...\"` or `path = \"kernels/add.cu\"`",
+            )
+        })?;
+
+        // the `.loc` directives of the source map
+        if source_map {
+            options.flags.push("-lineinfo".to_string());
+        }
+
+        Ok(CudaInput {
+            options,
+            kernel,
+            types,
+            module,
+            resource_usage,
+            source_map,
+            hot_reload,
+            target,
+        })
+    }
+}
+
+/// `NAME` or `NAME = value`
+struct Define {
+    name: String,
+    value: Option<String>,
+}
+
+impl Parse for Define {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse::<Ident>()?.to_string();
+        let value = if input.parse::<Option<Token![=]>>()?.is_some() {
+            Some(match input.parse::<Expr>()? {
+                Expr::Lit(lit) => lit_to_string(&lit.lit),
+                expr => quote::ToTokens::to_token_stream(&expr).to_string(),
+            })
+        } else {
+            None
+        };
+        Ok(Define { name, value })
+    }
+}
+
+fn lit_to_string(lit: &Lit) -> String {
+    match lit {
+        Lit::Str(lit) => lit.value(),
+        Lit::Int(lit) => lit.base10_digits().to_string(),
+        Lit::Float(lit) => lit.base10_digits().to_string(),
+        Lit::Bool(lit) => u8::from(lit.value).to_string(),
+        lit => quote::ToTokens::to_token_stream(lit).to_string(),
+    }
+}
+
+/// `["a", "b"]`
+fn parse_str_list(input: ParseStream) -> syn::Result<Vec<String>> {
+    let content;
+    bracketed!(content in input);
+    Ok(Punctuated::<LitStr, Token![,]>::parse_terminated(&content)?
+        .iter()
+        .map(LitStr::value)
+        .collect())
+}
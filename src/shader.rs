@@ -0,0 +1,387 @@
+use custos_macro_build::{
+    naga::{
+        proc::Layouter,
+        valid::{Capabilities, ModuleInfo},
+        AddressSpace, Module, ShaderStage, StorageAccess,
+    },
+    shader::capability,
+};
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    braced, bracketed,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    token, Ident, LitStr, Token,
+};
+
+use crate::{kernel_src::KernelSrc, options::InputForm};
+
+/// The language of a shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderLang {
+    Wgsl,
+    /// A GLSL compute shader.
+    Glsl,
+}
+
+impl ShaderLang {
+    /// GLSL shaders start with a `#version` directive.
+    fn detect(src: &str) -> ShaderLang {
+        let first_line = src.lines().map(str::trim).find(|line| !line.is_empty());
+        match first_line.is_some_and(|line| line.starts_with("#version")) {
+            true => ShaderLang::Glsl,
+            false => ShaderLang::Wgsl,
+        }
+    }
+
+    fn from_extension(path: &str) -> Option<ShaderLang> {
+        match path.rsplit_once('.')?.1 {
+            "wgsl" => Some(ShaderLang::Wgsl),
+            "glsl" | "comp" => Some(ShaderLang::Glsl),
+            _ => None,
+        }
+    }
+}
+
+/// The input of the shader macros: a bare string literal, `key = value` pairs or unquoted shader code.
+pub struct ShaderInput {
+    pub kernel: KernelSrc,
+    /// Set with `lang = "wgsl"` or `lang = "glsl"`, otherwise inferred from the file extension or a `#version` directive.
+    pub lang: ShaderLang,
+    /// Expands to a module with reflection data instead of an expression.
+    pub module: Option<Ident>,
+    /// Optional naga capabilities the shader may use, `capabilities = ["float64", "immediates"]`.
+    /// Without them, the shader is validated for what every wgpu adapter supports.
+    pub capabilities: Capabilities,
+}
+
+impl ShaderInput {
+    pub fn parse(input: TokenStream) -> syn::Result<ShaderInput> {
+        let kernel = match InputForm::parse(input)? {
+            InputForm::Lit(lit) => KernelSrc::from_lit(lit),
+            InputForm::KeyValues(input) => return Ok(input),
+            InputForm::Code(kernel) => kernel,
+        };
+        Ok(ShaderInput {
+            lang: ShaderLang::detect(&kernel.src),
+            kernel,
+            module: None,
+            capabilities: Capabilities::default(),
+        })
+    }
+}
+
+impl Parse for ShaderInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut src = None;
+        let mut lang = None;
+        let mut module = None;
+        let mut capabilities = Capabilities::default();
+
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+
+            match key.to_string().as_str() {
+                "src" if input.peek(token::Brace) => {
+                    let content;
+                    let brace = braced!(content in input);
+                    src = Some(KernelSrc::from_tokens(content.parse()?, brace.span.join()));
+                }
+                "src" => src = Some(KernelSrc::from_lit(input.parse()?)),
+                "path" => {
+                    let path: LitStr = input.parse()?;
+                    lang = lang.or(ShaderLang::from_extension(&path.value()));
+                    src = Some(KernelSrc::from_file(&path)?);
+                }
+                "lang" => {
+                    let value: LitStr = input.parse()?;
+                    lang = Some(match value.value().as_str() {
+                        "wgsl" => ShaderLang::Wgsl,
+                        "glsl" => ShaderLang::Glsl,
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.span(),
+                                "unknown shader language, expected `wgsl` or `glsl`",
+                            ))
+                        }
+                    });
+                }
+                "module" => module = Some(input.parse()?),
+                "capabilities" => {
+                    let content;
+                    bracketed!(content in input);
+                    for name in Punctuated::<LitStr, Token![,]>::parse_terminated(&content)? {
+                        capabilities |= capability(&name.value()).ok_or_else(|| {
+                            syn::Error::new(
+                                name.span(),
+                                "unknown capability, expected a naga capability in snake case, e.g. `float64` or `immediates`",
+                            )
+                        })?;
+                    }
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "unknown option, expected one of `src`, `path`, `lang`, `module`, `capabilities`",
+                    ))
+                }
+            }
+
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+
+        let kernel = src.ok_or_else(|| {
+            syn::Error::new(
+                Span::call_site(),
+                "missing shader source, e.g. `src = r\"...\"` or `path = \"shaders/add.wgsl\"`",
+            )
+        })?;
+
+        Ok(ShaderInput {
+            lang: lang.unwrap_or_else(|| ShaderLang::detect(&kernel.src)),
+            kernel,
+            module,
+            capabilities,
+        })
+    }
+}
+
+/// The items describing the entry points, bindings and push constants of a shader module:
+/// `BindingType`, `Binding`, `PushConstant`, `EntryPoint`, `ENTRY_POINTS` and a const per entry point.
+pub fn reflection_items(module: &Module, info: &ModuleInfo) -> TokenStream {
+    // the module was validated, which lays out its types as well
+    let mut layouter = Layouter::default();
+    let laid_out = layouter.update(module.to_ctx()).is_ok();
+
+    let entry_points = module
+        .entry_points
+        .iter()
+        .enumerate()
+        .map(|(idx, entry_point)| {
+            let uses = info.get_entry_point(idx);
+            let bindings = module
+                .global_variables
+                .iter()
+                .filter(|(handle, _)| !uses[*handle].is_empty())
+                .filter_map(|(_, global)| {
+                    let binding = global.binding.as_ref()?;
+                    let (group, binding) = (binding.group, binding.binding);
+                    let name = global.name.as_deref().unwrap_or_default();
+                    let ty = match global.space {
+                        AddressSpace::Uniform => quote!(BindingType::Uniform),
+                        AddressSpace::Storage { access } => {
+                            let read_only = !access.contains(StorageAccess::STORE);
+                            quote!(BindingType::Storage { read_only: #read_only })
+                        }
+                        _ => quote!(BindingType::Handle),
+                    };
+                    Some(quote! {
+                        Binding { group: #group, binding: #binding, name: #name, ty: #ty }
+                    })
+                });
+
+            let push_constant = module
+                .global_variables
+                .iter()
+                .find(|(handle, global)| {
+                    global.space == AddressSpace::Immediate && !uses[*handle].is_empty()
+                })
+                .map(|(_, global)| {
+                    let name = global.name.as_deref().unwrap_or_default();
+                    let size = match laid_out {
+                        true => layouter[global.ty].size,
+                        false => 0,
+                    };
+                    quote!(Some(PushConstant { name: #name, size: #size }))
+                })
+                .unwrap_or_else(|| quote!(None));
+
+            let name = &entry_point.name;
+            let stage = match entry_point.stage {
+                ShaderStage::Vertex => "vertex",
+                ShaderStage::Fragment => "fragment",
+                ShaderStage::Compute => "compute",
+                _ => "other",
+            };
+            let [x, y, z] = entry_point.workgroup_size;
+
+            let value = quote! {
+                EntryPoint {
+                    name: #name,
+                    stage: #stage,
+                    workgroup_size: [#x, #y, #z],
+                    bindings: &[#(#bindings),*],
+                    push_constant: #push_constant,
+                }
+            };
+            let ident = syn::parse_str::<Ident>(&name.to_uppercase())
+                .unwrap_or_else(|_| format_ident!("ENTRY_POINT_{idx}"));
+            (ident, value)
+        })
+        .collect::<Vec<_>>();
+
+    let consts = entry_points
+        .iter()
+        .map(|(ident, value)| quote!(pub const #ident: EntryPoint = #value;));
+    let idents = entry_points.iter().map(|(ident, _)| ident);
+
+    quote! {
+        /// How a binding is accessed.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum BindingType {
+            Uniform,
+            Storage { read_only: bool },
+            /// Textures and samplers.
+            Handle,
+        }
+
+        /// A resource binding used by an entry point, `@group(group) @binding(binding)`.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct Binding {
+            pub group: u32,
+            pub binding: u32,
+            pub name: &'static str,
+            pub ty: BindingType,
+        }
+
+        /// The push constant block used by an entry point, `var<immediate>` in WGSL.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct PushConstant {
+            pub name: &'static str,
+            /// The size of the block in bytes.
+            pub size: u32,
+        }
+
+        /// An entry point of the shader.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct EntryPoint {
+            pub name: &'static str,
+            /// `"compute"`, `"vertex"` or `"fragment"`.
+            pub stage: &'static str,
+            /// `@workgroup_size`, `[0, 0, 0]` for other stages.
+            pub workgroup_size: [u32; 3],
+            pub bindings: &'static [Binding],
+            pub push_constant: Option<PushConstant>,
+        }
+
+        /// All entry points of the shader.
+        pub const ENTRY_POINTS: &[EntryPoint] = &[#(#idents),*];
+
+        #(#consts)*
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use custos_macro_build::{
+        naga::valid::Capabilities,
+        shader::{capability, parse_wgsl, validate},
+    };
+    use quote::quote;
+
+    use super::{reflection_items, ShaderInput, ShaderLang};
+
+    const SHADER: &str = "
+        struct Params { scale: f32, offset: f32 }
+        var<immediate> params: Params;
+        @group(0) @binding(0) var<storage, read> x: array<f32>;
+        @group(0) @binding(1) var<storage, read_write> out: array<f32>;
+        @group(1) @binding(0) var<uniform> len: u32;
+
+        @compute @workgroup_size(64, 2)
+        fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+            if id.x < len {
+                out[id.x] = x[id.x] * params.scale + params.offset;
+            }
+        }
+
+        @compute @workgroup_size(1)
+        fn clear() {
+            out[0] = 0.0;
+        }
+    ";
+
+    #[test]
+    fn input_forms() {
+        let lit = ShaderInput::parse(quote!("#version 450\nvoid main() {}")).unwrap();
+        assert_eq!(lit.lang, ShaderLang::Glsl);
+        assert_eq!(lit.capabilities, Capabilities::default());
+
+        let braced = ShaderInput::parse(
+            quote!(module = fill, src = { @compute @workgroup_size(1) fn main() {} }),
+        )
+        .unwrap();
+        assert_eq!(braced.lang, ShaderLang::Wgsl);
+        assert!(
+            braced.kernel.src.contains("@workgroup_size(1"),
+            "{:?}",
+            braced.kernel.src
+        );
+        assert_eq!(braced.module.unwrap(), "fill");
+
+        let options = ShaderInput::parse(quote!(
+            src = "",
+            lang = "glsl",
+            capabilities = ["float64", "immediates"]
+        ))
+        .unwrap();
+        assert_eq!(options.lang, ShaderLang::Glsl);
+        assert_eq!(
+            options.capabilities,
+            Capabilities::default() | Capabilities::FLOAT64 | Capabilities::IMMEDIATES
+        );
+
+        let err = ShaderInput::parse(quote!(src = "", capabilities = ["f64"]))
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("unknown capability"), "{err}");
+    }
+
+    #[test]
+    fn reflects_entry_points_bindings_and_push_constants() {
+        let module = parse_wgsl(SHADER).unwrap();
+        let info = validate(&module, SHADER, capability("immediates").unwrap()).unwrap();
+        let items = reflection_items(&module, &info).to_string();
+
+        let main = quote! {
+            pub const MAIN: EntryPoint = EntryPoint {
+                name: "main",
+                stage: "compute",
+                workgroup_size: [64u32, 2u32, 1u32],
+                bindings: &[
+                    Binding { group: 0u32, binding: 0u32, name: "x", ty: BindingType::Storage { read_only: true } },
+                    Binding { group: 0u32, binding: 1u32, name: "out", ty: BindingType::Storage { read_only: false } },
+                    Binding { group: 1u32, binding: 0u32, name: "len", ty: BindingType::Uniform }
+                ],
+                push_constant: Some(PushConstant { name: "params", size: 8u32 }),
+            };
+        };
+        let clear = quote! {
+            pub const CLEAR: EntryPoint = EntryPoint {
+                name: "clear",
+                stage: "compute",
+                workgroup_size: [1u32, 1u32, 1u32],
+                bindings: &[
+                    Binding { group: 0u32, binding: 1u32, name: "out", ty: BindingType::Storage { read_only: false } }
+                ],
+                push_constant: None,
+            };
+        };
+        assert!(items.contains(&main.to_string()), "{items}");
+        assert!(items.contains(&clear.to_string()), "{items}");
+        assert!(
+            items.contains(
+                &quote!(
+                    pub const ENTRY_POINTS: &[EntryPoint] = &[MAIN, CLEAR];
+                )
+                .to_string()
+            ),
+            "{items}"
+        );
+    }
+}
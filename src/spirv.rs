@@ -0,0 +1,64 @@
+use custos_macro_build::shader::{parse_glsl, parse_wgsl, validate, write_spirv};
+use proc_macro2::TokenStream;
+use quote::quote;
+
+use crate::shader::{reflection_items, ShaderInput, ShaderLang};
+
+/// Expands `spirv!`: compiles a WGSL or GLSL compute shader to SPIR-V with naga.
+///
+/// The expression form expands to the SPIR-V module (`&'static [u32]`),
+/// `module = name` expands to a module with the SPIR-V and the entry points, bindings and push constants.
+pub fn spirv_expansion(input: TokenStream) -> TokenStream {
+    let ShaderInput {
+        kernel,
+        lang,
+        module,
+        capabilities,
+    } = match ShaderInput::parse(input) {
+        Ok(input) => input,
+        Err(err) => return err.to_compile_error(),
+    };
+    let track_files = kernel.track_files();
+    let src = &kernel.src;
+
+    let parsed = match lang {
+        ShaderLang::Wgsl => parse_wgsl(src),
+        ShaderLang::Glsl => parse_glsl(src),
+    };
+    let compiled = parsed.and_then(|shader| {
+        let info = validate(&shader, src, capabilities)?;
+        let words = write_spirv(&shader, &info)?;
+        Ok((shader, info, words))
+    });
+    let (shader, info, words) = match compiled {
+        Ok(compiled) => compiled,
+        Err(diagnostics) => {
+            let errors = kernel.emit_diagnostics("naga", &diagnostics);
+            return match module {
+                Some(_) => errors,
+                None => quote!({ #errors <&'static [u32]>::default() }),
+            };
+        }
+    };
+
+    let Some(module) = module else {
+        return quote!({
+            #track_files
+            const SPIRV: &[u32] = &[#(#words),*];
+            SPIRV
+        });
+    };
+
+    let items = reflection_items(&shader, &info);
+    quote! {
+        #[allow(dead_code)]
+        pub mod #module {
+            #track_files
+
+            /// The SPIR-V module.
+            pub const SPIRV: &[u32] = &[#(#words),*];
+
+            #items
+        }
+    }
+}
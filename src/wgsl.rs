@@ -0,0 +1,61 @@
+use custos_macro_build::shader::{parse_wgsl, validate};
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned};
+
+use crate::shader::{reflection_items, ShaderInput, ShaderLang};
+
+/// Expands `wgsl!`: parses and validates the shader with naga.
+///
+/// Errors are reported at their location in the shader. The expression form expands to the validated source (`&'static str`),
+/// `module = name` expands to a module with the source and the entry points and bindings.
+pub fn wgsl_expansion(input: TokenStream) -> TokenStream {
+    let ShaderInput {
+        kernel,
+        lang,
+        module,
+        capabilities,
+    } = match ShaderInput::parse(input) {
+        Ok(input) => input,
+        Err(err) => return err.to_compile_error(),
+    };
+    if lang == ShaderLang::Glsl {
+        return quote_spanned!(kernel.span()=> compile_error!("`wgsl!` expects WGSL, GLSL shaders are compiled with `spirv!`."));
+    }
+    let track_files = kernel.track_files();
+    let src = &kernel.src;
+
+    let checked = parse_wgsl(src).and_then(|module| {
+        let info = validate(&module, src, capabilities)?;
+        Ok((module, info))
+    });
+    let (shader, info) = match checked {
+        Ok(checked) => checked,
+        Err(diagnostics) => {
+            let errors = kernel.emit_diagnostics("naga", &diagnostics);
+            return match module {
+                Some(_) => errors,
+                None => quote!({ #errors "" }),
+            };
+        }
+    };
+
+    let Some(module) = module else {
+        return quote!({
+            #track_files
+            #src
+        });
+    };
+
+    let items = reflection_items(&shader, &info);
+    quote! {
+        #[allow(dead_code)]
+        pub mod #module {
+            #track_files
+
+            /// The validated WGSL source.
+            pub const SOURCE: &str = #src;
+
+            #items
+        }
+    }
+}